@@ -0,0 +1,22 @@
+#![no_main]
+
+use cntx::key::Keyset;
+use cntx::nca::NCA;
+use libfuzzer_sys::fuzz_target;
+
+// Without actual keys only plaintext NCAs get past the header, which is what gets fuzzed
+fuzz_target!(|data: &[u8]| {
+    let keyset = Keyset::default();
+    if let Ok(mut nca) = NCA::try_new(data, &keyset, None) {
+        let _ = nca.verify_fs_headers();
+        for idx in 0..nca.get_filesystem_count() {
+            if let Ok(mut fs) = nca.open_filesystem(idx) {
+                for file_path in fs.list_files().unwrap_or_default() {
+                    let mut buf = [0u8; 0x100];
+                    let _ = fs.read_file(&file_path, 0, &mut buf);
+                }
+            }
+        }
+        let _ = nca.verify();
+    }
+});
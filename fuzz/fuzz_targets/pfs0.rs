@@ -0,0 +1,15 @@
+#![no_main]
+
+use cntx::filesystem::Filesystem;
+use cntx::pfs0::PFS0;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut pfs0) = PFS0::try_new(data) {
+        for file_name in pfs0.list_files().unwrap_or_default() {
+            let mut buf = [0u8; 0x100];
+            let _ = Filesystem::get_file_size(&mut pfs0, &file_name);
+            let _ = Filesystem::read_file(&mut pfs0, &file_name, 0, &mut buf);
+        }
+    }
+});
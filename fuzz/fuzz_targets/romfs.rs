@@ -0,0 +1,17 @@
+#![no_main]
+
+use cntx::filesystem::Filesystem;
+use cntx::romfs::RomFs;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(mut romfs) = RomFs::try_new(data) {
+        let _ = romfs.list_dir(String::from("/"));
+        let _ = romfs.glob("**");
+        for file_path in Filesystem::list_files(&mut romfs).unwrap_or_default() {
+            let mut buf = [0u8; 0x100];
+            let _ = Filesystem::get_file_size(&mut romfs, &file_path);
+            let _ = Filesystem::read_file(&mut romfs, &file_path, 0, &mut buf);
+        }
+    }
+});
@@ -0,0 +1,381 @@
+use crate::error::{Error, Result};
+use crate::util::{Aes128CtrReader, LockShared, ReadSeek, Shared};
+use std::io::{self, Read, Seek, SeekFrom};
+
+// Both BKTR tables are bucket trees: a first node with the offsets of each bucket, followed by
+// the buckets themselves (all of them NODE_SIZE bytes long)
+pub const NODE_SIZE: usize = 0x4000;
+const NODE_HEADER_SIZE: usize = 0x10;
+
+fn read_u32(data: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+}
+
+fn read_u64(data: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(data[offset..offset + 8].try_into().unwrap())
+}
+
+fn parse_bucket_tree<T>(
+    data: &[u8],
+    entry_size: usize,
+    parse_entry: impl Fn(&[u8]) -> T,
+) -> Result<(Vec<T>, u64)> {
+    if data.len() < NODE_HEADER_SIZE {
+        return Err(Error::InvalidData(String::from("Invalid BKTR table")));
+    }
+
+    let bucket_count = read_u32(data, 0x4) as usize;
+    let end_offset = read_u64(data, 0x8);
+    let max_entry_count = (NODE_SIZE - NODE_HEADER_SIZE) / entry_size;
+
+    let mut entries: Vec<T> = Vec::new();
+    for bucket_idx in 0..bucket_count {
+        let bucket_offset = (bucket_idx + 1) * NODE_SIZE;
+        let bucket = data
+            .get(bucket_offset..bucket_offset + NODE_SIZE)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid BKTR bucket offset")))?;
+
+        let entry_count = read_u32(bucket, 0x4) as usize;
+        if entry_count > max_entry_count {
+            return Err(Error::InvalidData(String::from(
+                "Invalid BKTR bucket entry count",
+            )));
+        }
+
+        for entry_idx in 0..entry_count {
+            let entry_offset = NODE_HEADER_SIZE + entry_idx * entry_size;
+            entries.push(parse_entry(
+                &bucket[entry_offset..entry_offset + entry_size],
+            ));
+        }
+    }
+
+    Ok((entries, end_offset))
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct RelocationEntry {
+    pub virtual_offset: u64,
+    pub physical_offset: u64,
+    // Whether the data comes from the patch section (otherwise it comes from the base one)
+    pub is_patch: bool,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RelocationTable {
+    pub entries: Vec<RelocationEntry>,
+    pub virtual_size: u64,
+}
+
+impl RelocationTable {
+    pub const ENTRY_SIZE: usize = 0x14;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let (entries, virtual_size) =
+            parse_bucket_tree(data, Self::ENTRY_SIZE, |entry| RelocationEntry {
+                virtual_offset: read_u64(entry, 0x0),
+                physical_offset: read_u64(entry, 0x8),
+                is_patch: read_u32(entry, 0x10) != 0,
+            })?;
+
+        Ok(Self {
+            entries,
+            virtual_size,
+        })
+    }
+
+    // The entry containing the offset, and the size left until the next one
+    fn find_entry(&self, virtual_offset: u64) -> Option<(RelocationEntry, u64)> {
+        let entry_idx = self
+            .entries
+            .partition_point(|entry| entry.virtual_offset <= virtual_offset)
+            .checked_sub(1)?;
+        let entry = self.entries[entry_idx];
+        let entry_end = self
+            .entries
+            .get(entry_idx + 1)
+            .map(|next_entry| next_entry.virtual_offset)
+            .unwrap_or(self.virtual_size);
+
+        (virtual_offset < entry_end).then_some((entry, entry_end - virtual_offset))
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SubsectionEntry {
+    pub physical_offset: u64,
+    pub ctr: u32,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SubsectionTable {
+    pub entries: Vec<SubsectionEntry>,
+    pub physical_size: u64,
+}
+
+impl SubsectionTable {
+    pub const ENTRY_SIZE: usize = 0x10;
+
+    pub fn from_slice(data: &[u8]) -> Result<Self> {
+        let (entries, physical_size) =
+            parse_bucket_tree(data, Self::ENTRY_SIZE, |entry| SubsectionEntry {
+                physical_offset: read_u64(entry, 0x0),
+                ctr: read_u32(entry, 0xC),
+            })?;
+
+        Ok(Self {
+            entries,
+            physical_size,
+        })
+    }
+
+    fn find_entry(&self, physical_offset: u64) -> Option<(SubsectionEntry, u64)> {
+        let entry_idx = self
+            .entries
+            .partition_point(|entry| entry.physical_offset <= physical_offset)
+            .checked_sub(1)?;
+        let entry = self.entries[entry_idx];
+        let entry_end = self
+            .entries
+            .get(entry_idx + 1)
+            .map(|next_entry| next_entry.physical_offset)
+            .unwrap_or(self.physical_size);
+
+        (physical_offset < entry_end).then_some((entry, entry_end - physical_offset))
+    }
+}
+
+// The virtual (patched) section: relocated reads either go to the (already decrypted) base
+// section or to the patch section, whose subsections each have their own AES-CTR counter.
+// Without a base section only the parts stored in the patch section can be read
+pub struct BktrReader {
+    base_reader: Option<Shared<dyn ReadSeek>>,
+    patch_reader: Shared<dyn ReadSeek>,
+    patch_section_offset: u64,
+    patch_ctr: u64,
+    patch_key: Vec<u8>,
+    relocation_table: RelocationTable,
+    subsection_table: SubsectionTable,
+    offset: u64,
+}
+
+impl BktrReader {
+    pub fn new(
+        base_reader: Option<Shared<dyn ReadSeek>>,
+        patch_reader: Shared<dyn ReadSeek>,
+        patch_section_offset: u64,
+        patch_ctr: u64,
+        patch_key: Vec<u8>,
+        relocation_table: RelocationTable,
+        subsection_table: SubsectionTable,
+    ) -> Self {
+        Self {
+            base_reader,
+            patch_reader,
+            patch_section_offset,
+            patch_ctr,
+            patch_key,
+            relocation_table,
+            subsection_table,
+            offset: 0,
+        }
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.relocation_table.virtual_size
+    }
+
+    fn read_patch(&mut self, physical_offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let (subsection, subsection_left) = self
+            .subsection_table
+            .find_entry(physical_offset)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "No BKTR subsection found for offset 0x{:X}",
+                        physical_offset
+                    ),
+                )
+            })?;
+
+        // The subsection counter replaces the lower half of the section counter
+        let ctr = (self.patch_ctr & 0xFFFFFFFF00000000) | subsection.ctr as u64;
+        let read_size = std::cmp::min(buf.len() as u64, subsection_left) as usize;
+        let mut subsection_reader = Aes128CtrReader::new(
+            self.patch_reader.clone(),
+            self.patch_section_offset + physical_offset,
+            ctr,
+            self.patch_key.clone(),
+        );
+        subsection_reader.read_exact(&mut buf[..read_size])?;
+        Ok(read_size)
+    }
+}
+
+impl Read for BktrReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read_size = 0;
+        while (read_size < buf.len()) && (self.offset < self.get_size()) {
+            let (entry, entry_left) =
+                self.relocation_table
+                    .find_entry(self.offset)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("No BKTR relocation found for offset 0x{:X}", self.offset),
+                        )
+                    })?;
+
+            let chunk_size = std::cmp::min((buf.len() - read_size) as u64, entry_left) as usize;
+            let chunk = &mut buf[read_size..read_size + chunk_size];
+            let physical_offset = entry.physical_offset + (self.offset - entry.virtual_offset);
+            let chunk_read_size = if entry.is_patch {
+                self.read_patch(physical_offset, chunk)?
+            } else {
+                let mut base_reader = self
+                    .base_reader
+                    .as_ref()
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::NotFound,
+                            format!(
+                                "Data at offset 0x{:X} is only in the base section",
+                                self.offset
+                            ),
+                        )
+                    })?
+                    .lock_shared();
+                base_reader.seek(SeekFrom::Start(physical_offset))?;
+                base_reader.read_exact(chunk)?;
+                chunk_size
+            };
+
+            read_size += chunk_read_size;
+            self.offset += chunk_read_size as u64;
+        }
+
+        Ok(read_size)
+    }
+}
+
+impl Seek for BktrReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+            SeekFrom::End(offset) => self.get_size() as i64 + offset,
+        };
+
+        if new_offset < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of section",
+            ));
+        }
+
+        self.offset = new_offset as u64;
+        Ok(self.offset)
+    }
+}
+
+// Sparse sections only store part of their data: their table (in the relocation table format) maps
+// section offsets either to the stored data or, for the second storage, to zeros. The stored data
+// is still encrypted as if it was at its original offset, so this reader uses absolute NCA offsets
+// (starting at the section offset) and can then be decrypted like a regular section
+pub struct SparseReader {
+    data_reader: Shared<dyn ReadSeek>,
+    data_offset: u64,
+    section_offset: u64,
+    sparse_table: RelocationTable,
+    offset: u64,
+}
+
+impl SparseReader {
+    pub fn new(
+        data_reader: Shared<dyn ReadSeek>,
+        data_offset: u64,
+        section_offset: u64,
+        sparse_table: RelocationTable,
+    ) -> Self {
+        Self {
+            data_reader,
+            data_offset,
+            section_offset,
+            sparse_table,
+            offset: section_offset,
+        }
+    }
+
+    #[inline]
+    pub fn get_end_offset(&self) -> u64 {
+        self.section_offset + self.sparse_table.virtual_size
+    }
+}
+
+impl Read for SparseReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset < self.section_offset {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "Offset 0x{:X} is before the sparse section start",
+                    self.offset
+                ),
+            ));
+        }
+
+        let mut read_size = 0;
+        while (read_size < buf.len()) && (self.offset < self.get_end_offset()) {
+            let virtual_offset = self.offset - self.section_offset;
+            let (entry, entry_left) =
+                self.sparse_table
+                    .find_entry(virtual_offset)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("No sparse entry found for offset 0x{:X}", virtual_offset),
+                        )
+                    })?;
+
+            let chunk_size = std::cmp::min((buf.len() - read_size) as u64, entry_left) as usize;
+            let chunk = &mut buf[read_size..read_size + chunk_size];
+            if entry.is_patch {
+                chunk.fill(0);
+            } else {
+                let physical_offset =
+                    entry.physical_offset + (virtual_offset - entry.virtual_offset);
+                let mut data_reader = self.data_reader.lock_shared();
+                data_reader.seek(SeekFrom::Start(self.data_offset + physical_offset))?;
+                data_reader.read_exact(chunk)?;
+            }
+
+            read_size += chunk_size;
+            self.offset += chunk_size as u64;
+        }
+
+        Ok(read_size)
+    }
+}
+
+impl Seek for SparseReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.offset.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.get_end_offset().checked_add_signed(offset),
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(new_offset)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of NCA",
+            )),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+use crate::error::{Error, Result};
+use crate::util::ReadSeek;
+use std::io::SeekFrom;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ContentMetaType {
+    SystemProgram = 0x01,
+    SystemData = 0x02,
+    SystemUpdate = 0x03,
+    BootImagePackage = 0x04,
+    BootImagePackageSafe = 0x05,
+    Application = 0x80,
+    Patch = 0x81,
+    AddOnContent = 0x82,
+    Delta = 0x83,
+    DataPatch = 0x84,
+}
+
+impl ContentMetaType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x01 => Some(Self::SystemProgram),
+            0x02 => Some(Self::SystemData),
+            0x03 => Some(Self::SystemUpdate),
+            0x04 => Some(Self::BootImagePackage),
+            0x05 => Some(Self::BootImagePackageSafe),
+            0x80 => Some(Self::Application),
+            0x81 => Some(Self::Patch),
+            0x82 => Some(Self::AddOnContent),
+            0x83 => Some(Self::Delta),
+            0x84 => Some(Self::DataPatch),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum ContentType {
+    Meta,
+    Program,
+    Data,
+    Control,
+    HtmlDocument,
+    LegalInformation,
+    DeltaFragment,
+}
+
+impl ContentType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Meta),
+            1 => Some(Self::Program),
+            2 => Some(Self::Data),
+            3 => Some(Self::Control),
+            4 => Some(Self::HtmlDocument),
+            5 => Some(Self::LegalInformation),
+            6 => Some(Self::DeltaFragment),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ContentMetaHeader {
+    pub title_id: u64,
+    pub version: u32,
+    pub meta_type: ContentMetaType,
+    pub extended_header_size: u16,
+    pub content_count: u16,
+    pub content_meta_count: u16,
+    pub attributes: u8,
+    pub required_download_system_version: u32,
+}
+
+impl ContentMetaHeader {
+    pub const SIZE: usize = 0x20;
+
+    fn from_slice(data: &[u8]) -> Result<Self> {
+        let meta_type = ContentMetaType::from_u8(data[0xC])
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid content meta type")))?;
+
+        Ok(Self {
+            title_id: u64::from_le_bytes(data[0x0..0x8].try_into().unwrap()),
+            version: u32::from_le_bytes(data[0x8..0xC].try_into().unwrap()),
+            meta_type,
+            extended_header_size: u16::from_le_bytes(data[0xE..0x10].try_into().unwrap()),
+            content_count: u16::from_le_bytes(data[0x10..0x12].try_into().unwrap()),
+            content_meta_count: u16::from_le_bytes(data[0x12..0x14].try_into().unwrap()),
+            attributes: data[0x14],
+            required_download_system_version: u32::from_le_bytes(
+                data[0x18..0x1C].try_into().unwrap(),
+            ),
+        })
+    }
+}
+
+// Only the commonly used fields of the known extended headers are parsed
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum ExtendedHeader {
+    Application {
+        patch_id: u64,
+        required_system_version: u32,
+    },
+    Patch {
+        application_id: u64,
+        required_system_version: u32,
+    },
+    AddOnContent {
+        application_id: u64,
+        required_application_version: u32,
+    },
+    Other(Vec<u8>),
+}
+
+impl ExtendedHeader {
+    fn from_slice(meta_type: ContentMetaType, data: &[u8]) -> Self {
+        if data.len() < 0xC {
+            return Self::Other(data.to_vec());
+        }
+
+        let id = u64::from_le_bytes(data[0x0..0x8].try_into().unwrap());
+        let version = u32::from_le_bytes(data[0x8..0xC].try_into().unwrap());
+        match meta_type {
+            ContentMetaType::Application => Self::Application {
+                patch_id: id,
+                required_system_version: version,
+            },
+            ContentMetaType::Patch => Self::Patch {
+                application_id: id,
+                required_system_version: version,
+            },
+            ContentMetaType::AddOnContent => Self::AddOnContent {
+                application_id: id,
+                required_application_version: version,
+            },
+            _ => Self::Other(data.to_vec()),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ContentInfo {
+    pub hash: [u8; 0x20],
+    pub content_id: [u8; 0x10],
+    pub size: u64,
+    pub content_type: ContentType,
+    pub id_offset: u8,
+}
+
+impl ContentInfo {
+    pub const SIZE: usize = 0x38;
+
+    fn from_slice(data: &[u8]) -> Result<Self> {
+        let content_type = ContentType::from_u8(data[0x36])
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid content type")))?;
+
+        // The size is a 48-bit value
+        let mut size_buf = [0u8; 8];
+        size_buf[..6].copy_from_slice(&data[0x30..0x36]);
+
+        Ok(Self {
+            hash: data[0x0..0x20].try_into().unwrap(),
+            content_id: data[0x20..0x30].try_into().unwrap(),
+            size: u64::from_le_bytes(size_buf),
+            content_type,
+            id_offset: data[0x37],
+        })
+    }
+
+    // The content ID is also the NCA's file name (without the extension)
+    #[inline]
+    pub fn get_content_id_str(&self) -> String {
+        hex::encode(self.content_id)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CNMT {
+    pub header: ContentMetaHeader,
+    pub extended_header: ExtendedHeader,
+    pub content_infos: Vec<ContentInfo>,
+}
+
+impl CNMT {
+    pub fn from<R: ReadSeek>(mut reader: R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut header_data = [0u8; ContentMetaHeader::SIZE];
+        reader.read_exact(&mut header_data)?;
+        let header = ContentMetaHeader::from_slice(&header_data)?;
+
+        let mut extended_header_data = vec![0u8; header.extended_header_size as usize];
+        reader.read_exact(&mut extended_header_data)?;
+        let extended_header = ExtendedHeader::from_slice(header.meta_type, &extended_header_data);
+
+        let mut content_infos: Vec<ContentInfo> = Vec::with_capacity(header.content_count as usize);
+        let mut content_info_data = [0u8; ContentInfo::SIZE];
+        for _ in 0..header.content_count {
+            reader.read_exact(&mut content_info_data)?;
+            content_infos.push(ContentInfo::from_slice(&content_info_data)?);
+        }
+
+        Ok(Self {
+            header,
+            extended_header,
+            content_infos,
+        })
+    }
+
+    #[inline]
+    pub fn get_title_id(&self) -> u64 {
+        self.header.title_id
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> u32 {
+        self.header.version
+    }
+
+    #[inline]
+    pub fn get_meta_type(&self) -> ContentMetaType {
+        self.header.meta_type
+    }
+
+    #[inline]
+    pub fn get_content_infos(&self) -> &[ContentInfo] {
+        &self.content_infos
+    }
+
+    pub fn find_content_info(&self, content_type: ContentType) -> Option<&ContentInfo> {
+        self.content_infos
+            .iter()
+            .find(|content_info| content_info.content_type == content_type)
+    }
+}
@@ -0,0 +1,50 @@
+use crate::nca::{EncryptionType, FileSystemType, NCAVersion};
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    #[error("Invalid magic (expected 0x{expected:08X}, found 0x{found:08X})")]
+    InvalidMagic { expected: u32, found: u32 },
+    #[error("Keys not present in the keyset: {}", .0.join(", "))]
+    MissingKeys(Vec<String>),
+    #[error("A title key is required to decrypt the NCA and none was supplied")]
+    MissingTitleKey,
+    #[error(
+        "The ticket is personalized, its title key can't be decrypted without console-unique keys"
+    )]
+    PersonalizedTicket,
+    #[error("Unsupported encryption type: {0:?}")]
+    UnsupportedEncryption(EncryptionType),
+    #[error("Unsupported NCA version: {0:?}")]
+    UnsupportedVersion(NCAVersion),
+    #[error("Invalid filesystem type (actual type: {0:?})")]
+    InvalidFileSystemType(FileSystemType),
+    #[error("Invalid {field} value: {value:#04x}")]
+    InvalidEnumValue { field: &'static str, value: u8 },
+    #[error("Unsupported feature: {0}")]
+    UnsupportedFeature(String),
+    #[error("Crypto error: {0}")]
+    CryptoError(String),
+    #[error("Invalid index: {0}")]
+    InvalidIndex(usize),
+    #[error("Not found: {0}")]
+    NotFound(String),
+    #[error("Already exists: {0}")]
+    AlreadyExists(String),
+    #[error("Hash mismatch: {0}")]
+    HashMismatch(String),
+    #[error("Invalid data: {0}")]
+    InvalidData(String),
+    #[error("Invalid input: {0}")]
+    InvalidInput(String),
+    #[error("Unexpected EOF: {0}")]
+    UnexpectedEof(String),
+}
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+// The cipher crates have their own (unit-like) error types, only their message is kept
+pub(crate) fn crypto_error(err: impl std::fmt::Display) -> Error {
+    Error::CryptoError(err.to_string())
+}
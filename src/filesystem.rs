@@ -0,0 +1,60 @@
+use crate::error::Result;
+use crate::pfs0::PFS0;
+use crate::romfs::RomFs;
+
+// Common file access for the filesystems NCA sections can contain, paths being plain file names for
+// PFS0 and (full) paths for RomFs
+pub trait Filesystem: Send {
+    fn list_files(&mut self) -> Result<Vec<String>>;
+
+    fn exists_file(&mut self, path: &str) -> bool;
+
+    fn get_file_size(&mut self, path: &str) -> Result<usize>;
+
+    fn read_file(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize>;
+}
+
+impl Filesystem for PFS0 {
+    #[inline]
+    fn list_files(&mut self) -> Result<Vec<String>> {
+        PFS0::list_files(self)
+    }
+
+    #[inline]
+    fn exists_file(&mut self, path: &str) -> bool {
+        self.find_file(path).is_some()
+    }
+
+    #[inline]
+    fn get_file_size(&mut self, path: &str) -> Result<usize> {
+        self.get_file_size_by_name(path)
+    }
+
+    #[inline]
+    fn read_file(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        self.read_file_by_name(path, offset as usize, buf)
+    }
+}
+
+impl Filesystem for RomFs {
+    fn list_files(&mut self) -> Result<Vec<String>> {
+        self.walk()
+            .map(|entry| entry.map(|(path, _)| path))
+            .collect()
+    }
+
+    #[inline]
+    fn exists_file(&mut self, path: &str) -> bool {
+        RomFs::exists_file(self, String::from(path))
+    }
+
+    #[inline]
+    fn get_file_size(&mut self, path: &str) -> Result<usize> {
+        RomFs::get_file_size(self, String::from(path))
+    }
+
+    #[inline]
+    fn read_file(&mut self, path: &str, offset: u64, buf: &mut [u8]) -> Result<usize> {
+        RomFs::read_file(self, String::from(path), offset, buf)
+    }
+}
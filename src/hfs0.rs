@@ -0,0 +1,137 @@
+use crate::error::{Error, Result};
+use crate::pfs0::PFS0FileReader;
+use crate::util::{
+    get_string_table_name, reader_read_string_table, reader_read_val, LockShared, ReadSeek, Shared,
+};
+use std::io::{Seek, SeekFrom};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct Header {
+    pub magic: u32,
+    pub file_count: u32,
+    pub string_table_size: u32,
+    pub reserved: [u8; 0x4],
+}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"HFS0");
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+#[repr(C)]
+pub struct FileEntry {
+    pub offset: u64,
+    pub size: usize,
+    pub string_table_offset: u32,
+    pub hashed_region_size: u32,
+    pub reserved: [u8; 0x8],
+    pub hash: [u8; 0x20],
+}
+
+pub struct HFS0 {
+    reader: Shared<dyn ReadSeek>,
+    base_offset: u64,
+    header: Header,
+    file_entries: Vec<FileEntry>,
+    string_table: Vec<u8>,
+}
+
+impl HFS0 {
+    #[inline]
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        Self::new_at(reader, 0)
+    }
+
+    // For partitions which aren't at the start of the reader (nested ones in XCIs, for instance)
+    pub fn new_at(reader: Shared<dyn ReadSeek>, base_offset: u64) -> Result<Self> {
+        reader.lock_shared().seek(SeekFrom::Start(base_offset))?;
+        let header: Header = reader_read_val(&reader)?;
+        if header.magic != Header::MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: Header::MAGIC,
+                found: header.magic,
+            });
+        }
+
+        // Not preallocated, the count may be bogus
+        let mut file_entries: Vec<FileEntry> = Vec::new();
+
+        for _ in 0..header.file_count {
+            let file_entry: FileEntry = reader_read_val(&reader)?;
+            file_entries.push(file_entry);
+        }
+
+        let str_table = reader_read_string_table(&reader, header.string_table_size)?;
+
+        Ok(Self {
+            reader,
+            base_offset,
+            header,
+            file_entries,
+            string_table: str_table,
+        })
+    }
+
+    fn get_data_offset(&self) -> Result<u64> {
+        // The counts and sizes are u32s, so only the base offset can make this overflow
+        let header_size = std::mem::size_of::<Header>() as u64
+            + std::mem::size_of::<FileEntry>() as u64 * self.header.file_count as u64
+            + self.header.string_table_size as u64;
+        self.base_offset
+            .checked_add(header_size)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid HFS0 data offset")))
+    }
+
+    pub fn list_files(&self) -> Result<Vec<String>> {
+        self.file_entries
+            .iter()
+            .map(|entry| get_string_table_name(&self.string_table, entry.string_table_offset))
+            .collect()
+    }
+
+    pub fn get_file_entry(&self, idx: usize) -> Result<&FileEntry> {
+        self.file_entries.get(idx).ok_or(Error::InvalidIndex(idx))
+    }
+
+    // Absolute offset of the file data within the underlying reader
+    pub fn get_file_offset(&self, idx: usize) -> Result<u64> {
+        let entry = self.get_file_entry(idx)?;
+        self.get_data_offset()?
+            .checked_add(entry.offset)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid HFS0 file offset")))
+    }
+
+    pub fn get_file_size(&mut self, idx: usize) -> Result<usize> {
+        Ok(self.get_file_entry(idx)?.size)
+    }
+
+    pub fn read_file(&mut self, idx: usize, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        let entry = *self.get_file_entry(idx)?;
+        let read_end = offset
+            .checked_add(buf.len())
+            .ok_or_else(|| Error::InvalidInput(format!("Invalid read offset 0x{:X}", offset)))?;
+        if read_end > entry.size {
+            return Err(Error::UnexpectedEof(String::from("EOF reached")));
+        }
+
+        let read_offset = self
+            .get_file_offset(idx)?
+            .checked_add(offset as u64)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid HFS0 file offset")))?;
+
+        self.reader
+            .lock_shared()
+            .seek(SeekFrom::Start(read_offset))?;
+        Ok(self.reader.lock_shared().read(buf)?)
+    }
+
+    pub fn get_file_reader(&mut self, idx: usize) -> Result<PFS0FileReader> {
+        let entry = *self.get_file_entry(idx)?;
+        let read_offset = self.get_file_offset(idx)?;
+
+        let mut reader = PFS0FileReader::new(self.reader.clone(), read_offset, entry.size as u64);
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(reader)
+    }
+}
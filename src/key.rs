@@ -1,18 +1,72 @@
+use crate::error::{Error, Result};
+use crate::nca::KeyAreaEncryptionKeyIndex;
 use crate::util::ReadSeek;
 use hex::FromHex;
-use std::io::{BufRead, BufReader, Error, ErrorKind, Result};
+use std::collections::HashMap;
+use std::env;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, SeekFrom, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
 
-#[derive(Clone, Debug)]
+// The path in the environment variable, then the file in ~/.switch, then in the working directory
+fn get_default_paths(env_var_name: &str, file_name: &str) -> Vec<PathBuf> {
+    get_default_paths_from(
+        env::var_os(env_var_name).map(PathBuf::from),
+        env::var_os("HOME")
+            .or_else(|| env::var_os("USERPROFILE"))
+            .map(PathBuf::from),
+        file_name,
+    )
+}
+
+// Same, with the environment already looked up
+pub(crate) fn get_default_paths_from(
+    env_path: Option<PathBuf>,
+    home_path: Option<PathBuf>,
+    file_name: &str,
+) -> Vec<PathBuf> {
+    let mut paths: Vec<PathBuf> = Vec::new();
+    paths.extend(env_path);
+    if let Some(home_path) = home_path {
+        paths.push(home_path.join(".switch").join(file_name));
+    }
+    paths.push(PathBuf::from(file_name));
+    paths
+}
+
+pub(crate) fn open_first_file(paths: &[PathBuf], kind: &str) -> Result<File> {
+    match paths.iter().find(|path| path.is_file()) {
+        Some(path) => Ok(File::open(path)?),
+        None => Err(Error::NotFound(format!(
+            "{} (tried {})",
+            kind,
+            paths
+                .iter()
+                .map(|path| format!("'{}'", path.display()))
+                .collect::<Vec<_>>()
+                .join(", ")
+        ))),
+    }
+}
+
+#[derive(Clone, Debug, Default)]
 pub struct Keyset {
     pub header_key: [u8; 0x20],
     pub key_area_keys_application: Vec<[u8; 0x10]>,
     pub key_area_keys_ocean: Vec<[u8; 0x10]>,
     pub key_area_keys_system: Vec<[u8; 0x10]>,
     pub title_key_encryption_keys: Vec<[u8; 0x10]>,
+    // Public keys for the first NCA header signature, by signature key generation (dev ones for
+    // dev.keys), taking precedence over the built-in ones (nca::HEADER_FIXED_KEY_MODULI)
+    pub header_fixed_key_moduli: Vec<[u8; 0x100]>,
+    // Names of the keys which were loaded, and of the ones which were present but aren't used
+    pub loaded_key_names: Vec<String>,
+    pub unknown_key_names: Vec<String>,
 }
 
 impl Keyset {
-    fn get_key_name_idx(base_name: &str, name: &String) -> Option<usize> {
+    fn get_key_name_idx(base_name: &str, name: &str) -> Option<usize> {
         if name.starts_with(base_name) && (name.len() == base_name.len() + 2) {
             let idx_str = &name[name.len() - 2..];
             u8::from_str_radix(idx_str, 16).ok().map(|s| s as usize)
@@ -21,72 +75,374 @@ impl Keyset {
         }
     }
 
+    fn set_indexed_key<const N: usize>(keys: &mut Vec<[u8; N]>, idx: usize, key_data: [u8; N]) {
+        if idx >= keys.len() {
+            keys.resize(idx + 1, [0; N]);
+        }
+        keys[idx] = key_data;
+    }
+
+    fn parse_key<const N: usize>(name: &str, value: &str, line_idx: usize) -> Result<[u8; N]> {
+        let mut key_data = [0u8; N];
+        hex::decode_to_slice(value, &mut key_data).map_err(|err| {
+            Error::InvalidData(format!(
+                "Invalid value for key '{}' at line {}: {}",
+                name,
+                line_idx + 1,
+                err
+            ))
+        })?;
+        Ok(key_data)
+    }
+
+    #[inline]
     pub fn from<R: ReadSeek>(reader: R) -> Result<Self> {
-        let lines = BufReader::new(reader).lines();
-
-        let mut keyset = Keyset {
-            header_key: [0; 0x20],
-            key_area_keys_application: Vec::new(),
-            key_area_keys_ocean: Vec::new(),
-            key_area_keys_system: Vec::new(),
-            title_key_encryption_keys: Vec::new(),
-        };
+        Self::from_reader(reader)
+    }
 
-        for line_str in lines.flatten() {
-            // if let Ok(line_str) = line {
-            let items: Vec<_> = line_str.split('=').collect();
-            if items.len() != 2 {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "Invalid keyset key-value",
-                ));
-            }
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut keyset = Self::default();
 
-            let mut key = String::from(items[0]);
-            key.retain(|c| !c.is_whitespace());
-            let mut value = String::from(items[1]);
-            value.retain(|c| !c.is_whitespace());
+        for (line_idx, line) in BufReader::new(reader).lines().enumerate() {
+            let line_str = line?;
+            let line_str = line_str.split(['#', ';']).next().unwrap().trim();
+            if line_str.is_empty() {
+                continue;
+            }
 
-            let key_data = Vec::from_hex(value).expect("Invalid hex key");
+            let (key, value) = line_str.split_once('=').ok_or_else(|| {
+                Error::InvalidData(format!("Invalid keyset key-value at line {}", line_idx + 1))
+            })?;
+            let key = key.trim();
+            let value = value.trim();
 
             if key.eq("header_key") {
-                keyset.header_key = key_data.clone().try_into().unwrap();
-            } else if let Some(idx) = Self::get_key_name_idx("key_area_key_application_", &key) {
-                if idx >= keyset.key_area_keys_application.len() {
-                    keyset.key_area_keys_application.resize(idx, [0; 0x10]);
-                }
+                keyset.header_key = Self::parse_key(key, value, line_idx)?;
+            } else if let Some(idx) = Self::get_key_name_idx("key_area_key_application_", key) {
+                let key_data = Self::parse_key(key, value, line_idx)?;
+                Self::set_indexed_key(&mut keyset.key_area_keys_application, idx, key_data);
+            } else if let Some(idx) = Self::get_key_name_idx("key_area_key_ocean_", key) {
+                let key_data = Self::parse_key(key, value, line_idx)?;
+                Self::set_indexed_key(&mut keyset.key_area_keys_ocean, idx, key_data);
+            } else if let Some(idx) = Self::get_key_name_idx("key_area_key_system_", key) {
+                let key_data = Self::parse_key(key, value, line_idx)?;
+                Self::set_indexed_key(&mut keyset.key_area_keys_system, idx, key_data);
+            } else if let Some(idx) = Self::get_key_name_idx("titlekek_", key) {
+                let key_data = Self::parse_key(key, value, line_idx)?;
+                Self::set_indexed_key(&mut keyset.title_key_encryption_keys, idx, key_data);
+            } else if let Some(idx) = Self::get_key_name_idx("nca_hdr_fixed_key_modulus_", key) {
+                let key_data = Self::parse_key(key, value, line_idx)?;
+                Self::set_indexed_key(&mut keyset.header_fixed_key_moduli, idx, key_data);
+            } else {
+                keyset.unknown_key_names.push(String::from(key));
+                continue;
+            }
 
-                keyset
-                    .key_area_keys_application
-                    .insert(idx, key_data.clone().try_into().unwrap());
-            } else if let Some(idx) = Self::get_key_name_idx("key_area_key_ocean_", &key) {
-                if idx >= keyset.key_area_keys_ocean.len() {
-                    keyset.key_area_keys_ocean.resize(idx, [0; 0x10]);
-                }
+            keyset.loaded_key_names.push(String::from(key));
+        }
 
-                keyset
-                    .key_area_keys_ocean
-                    .insert(idx, key_data.clone().try_into().unwrap());
-            } else if let Some(idx) = Self::get_key_name_idx("key_area_key_system_", &key) {
-                if idx >= keyset.key_area_keys_system.len() {
-                    keyset.key_area_keys_system.resize(idx, [0; 0x10]);
-                }
+        Ok(keyset)
+    }
+
+    // $SWITCH_KEYS, then ~/.switch/prod.keys, then ./prod.keys
+    #[inline]
+    pub fn get_default_paths() -> Vec<PathBuf> {
+        get_default_paths("SWITCH_KEYS", "prod.keys")
+    }
 
-                keyset
-                    .key_area_keys_system
-                    .insert(idx, key_data.clone().try_into().unwrap());
-            } else if let Some(idx) = Self::get_key_name_idx("titlekek_", &key) {
-                if idx >= keyset.title_key_encryption_keys.len() {
-                    keyset.title_key_encryption_keys.resize(idx, [0; 0x10]);
+    pub fn open_default() -> Result<Self> {
+        Self::from_reader(open_first_file(&Self::get_default_paths(), "keyset")?)
+    }
+
+    #[inline]
+    pub fn has_key(&self, name: &str) -> bool {
+        self.loaded_key_names
+            .iter()
+            .any(|key_name| key_name == name)
+    }
+
+    // Writes every present key as "name = hex" lines sorted by name, in the format from_reader reads.
+    // Unloaded all-zero keys (like the gaps between indexed keys) are left out, and unknown keys
+    // aren't kept when parsing so they can't be written back
+    pub fn write_to(&self, mut out: impl Write) -> Result<()> {
+        let is_present = |name: &str, key: &[u8]| key.iter().any(|b| *b != 0) || self.has_key(name);
+
+        let mut keys: Vec<(String, String)> = Vec::new();
+        if is_present("header_key", &self.header_key) {
+            keys.push((String::from("header_key"), hex::encode(self.header_key)));
+        }
+        for (base_name, indexed_keys) in [
+            ("key_area_key_application_", &self.key_area_keys_application),
+            ("key_area_key_ocean_", &self.key_area_keys_ocean),
+            ("key_area_key_system_", &self.key_area_keys_system),
+            ("titlekek_", &self.title_key_encryption_keys),
+        ] {
+            for (idx, key) in indexed_keys.iter().enumerate() {
+                let name = format!("{}{:02x}", base_name, idx);
+                if is_present(&name, key) {
+                    keys.push((name, hex::encode(key)));
                 }
+            }
+        }
+        for (idx, modulus) in self.header_fixed_key_moduli.iter().enumerate() {
+            let name = format!("nca_hdr_fixed_key_modulus_{:02x}", idx);
+            if is_present(&name, modulus) {
+                keys.push((name, hex::encode(modulus)));
+            }
+        }
+        keys.sort();
+
+        for (name, key) in keys {
+            writeln!(out, "{} = {}", name, key)?;
+        }
+        Ok(())
+    }
+
+    pub fn get_key_area_keys(&self, index: KeyAreaEncryptionKeyIndex) -> &[[u8; 0x10]] {
+        match index {
+            KeyAreaEncryptionKeyIndex::Application => &self.key_area_keys_application,
+            KeyAreaEncryptionKeyIndex::Ocean => &self.key_area_keys_ocean,
+            KeyAreaEncryptionKeyIndex::System => &self.key_area_keys_system,
+        }
+    }
+
+    // Names (as in prod.keys) of the keys needed for an NCA of the given key generation which aren't
+    // in the keyset. NCAs with title key crypto only need the titlekek, their key area isn't used
+    pub fn missing_keys_for(
+        &self,
+        key_gen: u8,
+        index: KeyAreaEncryptionKeyIndex,
+        needs_title_key: bool,
+    ) -> Vec<String> {
+        let (keys, name) = match needs_title_key {
+            true => (
+                self.title_key_encryption_keys.as_slice(),
+                format!("titlekek_{:02x}", key_gen),
+            ),
+            false => (
+                self.get_key_area_keys(index),
+                format!("key_area_key_{}_{:02x}", index, key_gen),
+            ),
+        };
+
+        match (key_gen as usize) < keys.len() {
+            true => Vec::new(),
+            false => vec![name],
+        }
+    }
+}
+
+impl FromStr for Keyset {
+    type Err = Error;
+
+    #[inline]
+    fn from_str(keys_str: &str) -> Result<Self> {
+        Self::from_reader(keys_str.as_bytes())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u32)]
+pub enum TicketSignatureType {
+    Rsa4096Sha1 = 0x10000,
+    Rsa2048Sha1 = 0x10001,
+    EcdsaSha1 = 0x10002,
+    Rsa4096Sha256 = 0x10003,
+    Rsa2048Sha256 = 0x10004,
+    EcdsaSha256 = 0x10005,
+}
+
+impl TicketSignatureType {
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0x10000 => Some(Self::Rsa4096Sha1),
+            0x10001 => Some(Self::Rsa2048Sha1),
+            0x10002 => Some(Self::EcdsaSha1),
+            0x10003 => Some(Self::Rsa4096Sha256),
+            0x10004 => Some(Self::Rsa2048Sha256),
+            0x10005 => Some(Self::EcdsaSha256),
+            _ => None,
+        }
+    }
 
-                keyset
-                    .title_key_encryption_keys
-                    .insert(idx, key_data.clone().try_into().unwrap());
+    // Signature type + signature + padding, the ticket data starts right after
+    pub fn get_data_offset(self) -> usize {
+        match self {
+            Self::Rsa4096Sha1 | Self::Rsa4096Sha256 => 0x4 + 0x200 + 0x3C,
+            Self::Rsa2048Sha1 | Self::Rsa2048Sha256 => 0x4 + 0x100 + 0x3C,
+            Self::EcdsaSha1 | Self::EcdsaSha256 => 0x4 + 0x3C + 0x40,
+        }
+    }
+}
+
+// Personalized title keys are additionally RSA-encrypted with a console-unique key
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum TitleKeyType {
+    Common = 0,
+    Personalized = 1,
+}
+
+impl TitleKeyType {
+    pub fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0 => Some(Self::Common),
+            1 => Some(Self::Personalized),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Ticket {
+    pub signature_type: TicketSignatureType,
+    pub title_key_block: [u8; 0x100],
+    pub key_generation: u8,
+    pub rights_id: [u8; 0x10],
+}
+
+impl Ticket {
+    const TITLE_KEY_BLOCK_OFFSET: usize = 0x40;
+    const TITLE_KEY_TYPE_OFFSET: usize = 0x141;
+    const KEY_GENERATION_OFFSET: usize = 0x145;
+    const RIGHTS_ID_OFFSET: usize = 0x160;
+    const DATA_SIZE: usize = 0x180;
+
+    fn read_data<R: ReadSeek>(mut reader: R) -> Result<(TicketSignatureType, Vec<u8>)> {
+        let mut sig_type_buf = [0u8; 4];
+        reader.read_exact(&mut sig_type_buf)?;
+        let signature_type = TicketSignatureType::from_u32(u32::from_le_bytes(sig_type_buf))
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid ticket signature type")))?;
+
+        reader.seek(SeekFrom::Start(signature_type.get_data_offset() as u64))?;
+        let mut data = vec![0u8; Self::DATA_SIZE];
+        reader.read_exact(&mut data)?;
+        Ok((signature_type, data))
+    }
+
+    // Works for any ticket, unlike from which rejects personalized ones
+    pub fn read_rights_id<R: ReadSeek>(reader: R) -> Result<[u8; 0x10]> {
+        let (_, data) = Self::read_data(reader)?;
+        Ok(data[Self::RIGHTS_ID_OFFSET..Self::RIGHTS_ID_OFFSET + 0x10]
+            .try_into()
+            .unwrap())
+    }
+
+    pub fn from<R: ReadSeek>(reader: R) -> Result<Self> {
+        let (signature_type, data) = Self::read_data(reader)?;
+
+        // Only common tickets can be used with just the keyset
+        match TitleKeyType::from_u8(data[Self::TITLE_KEY_TYPE_OFFSET]) {
+            Some(TitleKeyType::Common) => {}
+            Some(TitleKeyType::Personalized) => return Err(Error::PersonalizedTicket),
+            None => {
+                return Err(Error::InvalidData(format!(
+                    "Invalid ticket title key type {}",
+                    data[Self::TITLE_KEY_TYPE_OFFSET]
+                )))
             }
-            // }
         }
 
-        Ok(keyset)
+        Ok(Self {
+            signature_type,
+            title_key_block: data
+                [Self::TITLE_KEY_BLOCK_OFFSET..Self::TITLE_KEY_BLOCK_OFFSET + 0x100]
+                .try_into()
+                .unwrap(),
+            key_generation: data[Self::KEY_GENERATION_OFFSET],
+            rights_id: data[Self::RIGHTS_ID_OFFSET..Self::RIGHTS_ID_OFFSET + 0x10]
+                .try_into()
+                .unwrap(),
+        })
+    }
+
+    // The (still encrypted) title key, as expected by NCA::new
+    #[inline]
+    pub fn get_title_key(&self) -> [u8; 0x10] {
+        self.title_key_block[..0x10].try_into().unwrap()
+    }
+
+    #[inline]
+    pub fn get_rights_id(&self) -> [u8; 0x10] {
+        self.rights_id
+    }
+
+    #[inline]
+    pub fn matches_rights_id(&self, rights_id: &[u8; 0x10]) -> bool {
+        self.rights_id == *rights_id
+    }
+}
+
+// Encrypted title keys (as stored in tickets) by rights ID, usually loaded from a title.keys file
+#[derive(Clone, Debug, Default)]
+pub struct TitleKeys {
+    keys: HashMap<[u8; 0x10], [u8; 0x10]>,
+}
+
+impl TitleKeys {
+    #[inline]
+    pub fn from<R: ReadSeek>(reader: R) -> Result<Self> {
+        Self::from_reader(reader)
+    }
+
+    pub fn from_reader<R: Read>(reader: R) -> Result<Self> {
+        let mut keys: HashMap<[u8; 0x10], [u8; 0x10]> = HashMap::new();
+
+        for (line_idx, line) in BufReader::new(reader).lines().enumerate() {
+            let line_str = line?;
+            // Comments can either take a whole line or follow an entry
+            let line_str = line_str.split(['#', ';']).next().unwrap().trim();
+            if line_str.is_empty() {
+                continue;
+            }
+
+            let invalid_line =
+                || Error::InvalidData(format!("Invalid title key entry at line {}", line_idx + 1));
+            let (rights_id, title_key) = line_str.split_once('=').ok_or_else(invalid_line)?;
+            let rights_id = <[u8; 0x10]>::from_hex(rights_id.trim()).map_err(|_| invalid_line())?;
+            let title_key = <[u8; 0x10]>::from_hex(title_key.trim()).map_err(|_| invalid_line())?;
+            keys.insert(rights_id, title_key);
+        }
+
+        Ok(Self { keys })
+    }
+
+    // $SWITCH_TITLE_KEYS, then ~/.switch/title.keys, then ./title.keys
+    #[inline]
+    pub fn get_default_paths() -> Vec<PathBuf> {
+        get_default_paths("SWITCH_TITLE_KEYS", "title.keys")
+    }
+
+    pub fn open_default() -> Result<Self> {
+        Self::from_reader(open_first_file(&Self::get_default_paths(), "title keys")?)
+    }
+
+    #[inline]
+    pub fn get(&self, rights_id: &[u8; 0x10]) -> Option<[u8; 0x10]> {
+        self.keys.get(rights_id).copied()
+    }
+
+    #[inline]
+    pub fn insert(&mut self, rights_id: [u8; 0x10], title_key: [u8; 0x10]) {
+        self.keys.insert(rights_id, title_key);
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.keys.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.keys.is_empty()
+    }
+}
+
+impl FromStr for TitleKeys {
+    type Err = Error;
+
+    #[inline]
+    fn from_str(keys_str: &str) -> Result<Self> {
+        Self::from_reader(keys_str.as_bytes())
     }
 }
@@ -0,0 +1,106 @@
+use crate::error::{Error, Result};
+use crate::util::ReadSeek;
+use std::io::SeekFrom;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(u8)]
+pub enum Language {
+    AmericanEnglish,
+    BritishEnglish,
+    Japanese,
+    French,
+    German,
+    LatinAmericanSpanish,
+    Spanish,
+    Italian,
+    Dutch,
+    CanadianFrench,
+    Portuguese,
+    Russian,
+    Korean,
+    TraditionalChinese,
+    SimplifiedChinese,
+    BrazilianPortuguese,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct Title {
+    pub name: String,
+    pub publisher: String,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NACP {
+    pub titles: Vec<Title>,
+    pub supported_language_flag: u32,
+    pub presence_group_id: u64,
+    pub display_version: String,
+}
+
+fn read_str(data: &[u8]) -> Result<String> {
+    let str_len = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+    String::from_utf8(data[..str_len].to_vec())
+        .map_err(|_| Error::InvalidData(String::from("Invalid NACP string")))
+}
+
+impl NACP {
+    pub const TITLE_COUNT: usize = 16;
+    const TITLE_NAME_SIZE: usize = 0x200;
+    const TITLE_PUBLISHER_SIZE: usize = 0x100;
+    const SUPPORTED_LANGUAGE_FLAG_OFFSET: usize = 0x302C;
+    const PRESENCE_GROUP_ID_OFFSET: usize = 0x3038;
+    const DISPLAY_VERSION_OFFSET: usize = 0x3060;
+    const DISPLAY_VERSION_SIZE: usize = 0x10;
+    pub const SIZE: usize = 0x4000;
+
+    pub fn from<R: ReadSeek>(mut reader: R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut data = vec![0u8; Self::SIZE];
+        reader.read_exact(&mut data)?;
+
+        let title_size = Self::TITLE_NAME_SIZE + Self::TITLE_PUBLISHER_SIZE;
+        let mut titles: Vec<Title> = Vec::with_capacity(Self::TITLE_COUNT);
+        for title_data in data[..title_size * Self::TITLE_COUNT].chunks(title_size) {
+            titles.push(Title {
+                name: read_str(&title_data[..Self::TITLE_NAME_SIZE])?,
+                publisher: read_str(&title_data[Self::TITLE_NAME_SIZE..])?,
+            });
+        }
+
+        Ok(Self {
+            titles,
+            supported_language_flag: u32::from_le_bytes(
+                data[Self::SUPPORTED_LANGUAGE_FLAG_OFFSET
+                    ..Self::SUPPORTED_LANGUAGE_FLAG_OFFSET + 4]
+                    .try_into()
+                    .unwrap(),
+            ),
+            presence_group_id: u64::from_le_bytes(
+                data[Self::PRESENCE_GROUP_ID_OFFSET..Self::PRESENCE_GROUP_ID_OFFSET + 8]
+                    .try_into()
+                    .unwrap(),
+            ),
+            display_version: read_str(
+                &data[Self::DISPLAY_VERSION_OFFSET
+                    ..Self::DISPLAY_VERSION_OFFSET + Self::DISPLAY_VERSION_SIZE],
+            )?,
+        })
+    }
+
+    // Titles for unsupported languages are usually left empty
+    pub fn get_title(&self, language: Language) -> Option<&Title> {
+        self.titles
+            .get(language as usize)
+            .filter(|title| !title.name.is_empty())
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> &str {
+        &self.display_version
+    }
+
+    #[inline]
+    pub fn supports_language(&self, language: Language) -> bool {
+        (self.supported_language_flag & (1 << language as u32)) != 0
+    }
+}
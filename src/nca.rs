@@ -1,16 +1,32 @@
-use crate::key::Keyset;
+use crate::bktr::{BktrReader, RelocationEntry, RelocationTable, SparseReader, SubsectionTable};
+use crate::error::{crypto_error, Error, Result};
+use crate::filesystem::Filesystem;
+use crate::key::{Keyset, TitleKeys};
+use crate::npdm::NPDM;
 use crate::pfs0::PFS0;
-use crate::romfs::RomFs;
-use crate::util::{get_nintendo_tweak, new_shared, Aes128CtrReader, ReadSeek, Shared};
+use crate::romfs::{IvfcLevel, IvfcReader, RomFs};
+use crate::util::{
+    aes128_ctr_crypt, align_up, get_nintendo_tweak, new_shared, new_shared_bytes, read_to_vec,
+    sha256_reader, Aes128CtrReader, Aes128XtsReader, LockShared, ReadSeek, Shared, SubReader,
+};
 use aes::Aes128;
 use aes::NewBlockCipher;
 use block_modes::block_padding::NoPadding;
 use block_modes::BlockMode;
 use block_modes::Ecb;
-use std::io::{Error, ErrorKind, Result};
+use hex::FromHex;
+use rsa::{BigUint, Pss, RsaPublicKey};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::str::FromStr;
 use xts_mode::Xts128;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum DistributionType {
     System,
@@ -18,6 +34,7 @@ pub enum DistributionType {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum ContentType {
     Program,
@@ -28,6 +45,100 @@ pub enum ContentType {
     PublicData,
 }
 
+// Canonical (lowercase) names, as used in logs and CLIs
+macro_rules! impl_display_names {
+    ($enum_type:ty { $($variant:ident => $name:literal),* $(,)? }) => {
+        impl fmt::Display for $enum_type {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(match self {
+                    $(Self::$variant => $name),*
+                })
+            }
+        }
+    };
+}
+
+// Header enums are read from untrusted bytes, so every discriminant must be checked first
+macro_rules! impl_try_from_u8 {
+    ($enum_type:ty, $field:literal { $($value:literal => $variant:ident),* $(,)? }) => {
+        impl TryFrom<u8> for $enum_type {
+            type Error = Error;
+
+            fn try_from(value: u8) -> Result<Self> {
+                match value {
+                    $($value => Ok(Self::$variant),)*
+                    _ => Err(Error::InvalidEnumValue {
+                        field: $field,
+                        value,
+                    }),
+                }
+            }
+        }
+    };
+}
+
+impl_try_from_u8!(DistributionType, "distribution type" {
+    0 => System,
+    1 => Gamecard,
+});
+
+impl_display_names!(DistributionType {
+    System => "system",
+    Gamecard => "gamecard",
+});
+
+impl FromStr for DistributionType {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "system" => Ok(Self::System),
+            "gamecard" => Ok(Self::Gamecard),
+            _ => Err(Error::InvalidInput(format!(
+                "Unknown distribution type '{}' (expected system or gamecard)",
+                name
+            ))),
+        }
+    }
+}
+
+impl_try_from_u8!(ContentType, "content type" {
+    0 => Program,
+    1 => Meta,
+    2 => Control,
+    3 => Manual,
+    4 => Data,
+    5 => PublicData,
+});
+
+impl_display_names!(ContentType {
+    Program => "program",
+    Meta => "meta",
+    Control => "control",
+    Manual => "manual",
+    Data => "data",
+    PublicData => "public_data",
+});
+
+impl FromStr for ContentType {
+    type Err = Error;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "program" => Ok(Self::Program),
+            "meta" => Ok(Self::Meta),
+            "control" => Ok(Self::Control),
+            "manual" => Ok(Self::Manual),
+            "data" => Ok(Self::Data),
+            "public_data" => Ok(Self::PublicData),
+            _ => Err(Error::InvalidInput(format!(
+                "Unknown content type '{}' (expected program, meta, control, manual, data or public_data)",
+                name
+            ))),
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct RSASignature {
@@ -41,6 +152,14 @@ pub struct RSASignature {
     part_8: [u8; 0x20],
 }
 
+impl RSASignature {
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
+        }
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct SdkAddonVersion {
@@ -50,6 +169,57 @@ pub struct SdkAddonVersion {
     major: u8,
 }
 
+impl SdkAddonVersion {
+    pub const fn new(major: u8, minor: u8, micro: u8) -> Self {
+        Self {
+            unk: 0,
+            micro,
+            minor,
+            major,
+        }
+    }
+
+    #[inline]
+    pub fn get_major(&self) -> u8 {
+        self.major
+    }
+
+    #[inline]
+    pub fn get_minor(&self) -> u8 {
+        self.minor
+    }
+
+    #[inline]
+    pub fn get_micro(&self) -> u8 {
+        self.micro
+    }
+}
+
+// Field order is the reverse of the significance order
+impl Ord for SdkAddonVersion {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.major, self.minor, self.micro, self.unk).cmp(&(
+            other.major,
+            other.minor,
+            other.micro,
+            other.unk,
+        ))
+    }
+}
+
+impl PartialOrd for SdkAddonVersion {
+    #[inline]
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl fmt::Display for SdkAddonVersion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{}.{}", self.major, self.minor, self.micro)
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct FileSystemEntry {
@@ -65,6 +235,7 @@ pub struct Sha256Hash {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum KeyAreaEncryptionKeyIndex {
     Application,
@@ -72,6 +243,18 @@ pub enum KeyAreaEncryptionKeyIndex {
     System,
 }
 
+impl_try_from_u8!(KeyAreaEncryptionKeyIndex, "key area key index" {
+    0 => Application,
+    1 => Ocean,
+    2 => System,
+});
+
+impl_display_names!(KeyAreaEncryptionKeyIndex {
+    Application => "application",
+    Ocean => "ocean",
+    System => "system",
+});
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
 pub struct KeyArea {
@@ -108,11 +291,32 @@ impl KeyArea {
             std::slice::from_raw_parts_mut(self as *mut _ as *mut u8, std::mem::size_of::<Self>())
         }
     }
+
+    #[inline]
+    pub fn get_aes_xts_key(&self) -> &[u8; 0x20] {
+        &self.aes_xts_key
+    }
+
+    #[inline]
+    pub fn get_aes_ctr_key(&self) -> &[u8; 0x10] {
+        &self.aes_ctr_key
+    }
 }
 
 pub const MAX_FILESYSTEM_COUNT: usize = 4;
 pub const SECTOR_SIZE: usize = 0x200;
 pub const MEDIA_UNIT_SIZE: usize = 0x200;
+// Header and filesystem headers
+pub const HEADERS_SIZE: usize = 0xC00;
+
+// Built-in public keys for the first header signature (hactool's nca_hdr_fixed_key_modulus ones), by
+// signature key generation. Generations missing here have to come from the keyset, see
+// NCA::verify_header_signature_with_keyset
+pub const HEADER_FIXED_KEY_MODULI: &[[u8; 0x100]] = &[];
+pub const HEADER_FIXED_KEY_MODULI_DEV: &[[u8; 0x100]] = &[];
+pub const HEADER_FIXED_KEY_EXPONENT: u32 = 0x10001;
+// Salt length used by the header RSA-PSS signatures
+pub const HEADER_SIGNATURE_SALT_LEN: usize = 0x20;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
@@ -143,9 +347,130 @@ pub struct Header {
     pub reserved_6: [u8; 0x20],
 }
 
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
+pub enum NCAVersion {
+    NCA0,
+    NCA2,
+    NCA3,
+}
+
+impl NCAVersion {
+    pub const NCA0_MAGIC: u32 = u32::from_le_bytes(*b"NCA0");
+    pub const NCA2_MAGIC: u32 = u32::from_le_bytes(*b"NCA2");
+    pub const NCA3_MAGIC: u32 = u32::from_le_bytes(*b"NCA3");
+
+    pub fn from_magic(magic: u32) -> Option<Self> {
+        match magic {
+            Self::NCA0_MAGIC => Some(Self::NCA0),
+            Self::NCA2_MAGIC => Some(Self::NCA2),
+            Self::NCA3_MAGIC => Some(Self::NCA3),
+            _ => None,
+        }
+    }
+
+    pub fn get_magic(self) -> u32 {
+        match self {
+            Self::NCA0 => Self::NCA0_MAGIC,
+            Self::NCA2 => Self::NCA2_MAGIC,
+            Self::NCA3 => Self::NCA3_MAGIC,
+        }
+    }
+
+    // NCA3 encrypts the filesystem headers as the sectors following the header, NCA2 encrypts each one
+    // on its own as sector 0. NCA0 does the same as NCA3, but with the key area XTS key (so `xts` has
+    // to be that one) instead of the header key
+    fn decrypt_fs_headers(self, xts: &Xts128<Aes128>, fs_headers_buf: &mut [u8]) -> Result<()> {
+        match self {
+            Self::NCA2 => {
+                for fs_header_buf in fs_headers_buf.chunks_mut(SECTOR_SIZE) {
+                    xts.decrypt_area(fs_header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+                }
+            }
+            Self::NCA0 | Self::NCA3 => {
+                xts.decrypt_area(fs_headers_buf, SECTOR_SIZE, 2, get_nintendo_tweak)
+            }
+        }
+        Ok(())
+    }
+
+    fn encrypt_fs_headers(self, xts: &Xts128<Aes128>, fs_headers_buf: &mut [u8]) -> Result<()> {
+        match self {
+            Self::NCA0 => return Err(Error::UnsupportedVersion(self)),
+            Self::NCA2 => {
+                for fs_header_buf in fs_headers_buf.chunks_mut(SECTOR_SIZE) {
+                    xts.encrypt_area(fs_header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+                }
+            }
+            Self::NCA3 => xts.encrypt_area(fs_headers_buf, SECTOR_SIZE, 2, get_nintendo_tweak),
+        }
+        Ok(())
+    }
+}
+
+// The headers are copied as-is from their on-disk bytes, so the layouts must match exactly
+const _: () = assert!(std::mem::size_of::<Header>() == Header::SIZE);
+const _: () = assert!(std::mem::size_of::<FileSystemHeader>() == FileSystemHeader::SIZE);
+const _: () = assert!(HEADERS_SIZE == Header::SIZE + FileSystemHeader::SIZE * MAX_FILESYSTEM_COUNT);
+
 impl Header {
-    pub const MAGIC: u32 = u32::from_le_bytes(*b"NCA3");
+    pub const MAGIC: u32 = NCAVersion::NCA3_MAGIC;
+    pub const SIZE: usize = 0x400;
+
+    // Checks the enum fields on the raw (decrypted) bytes before copying them, so that a Header never
+    // holds an invalid discriminant. The magic isn't checked here
+    pub fn from_bytes(data: &[u8; Self::SIZE]) -> Result<Self> {
+        let dist_type = DistributionType::try_from(data[0x204])?;
+        ContentType::try_from(data[0x205])?;
+        // Gamecard NCAs use the same key area keys as the other ones, so an unknown index means there's
+        // no key to pick at all (and the application one must not be used instead)
+        KeyAreaEncryptionKeyIndex::try_from(data[0x207]).inspect_err(|_| {
+            log::debug!(
+                "Unknown key area key index {} ({} distribution)",
+                data[0x207],
+                dist_type
+            )
+        })?;
+
+        Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const Self) })
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
+        }
+    }
+
+    // Everything after both signatures (0x200..0x400) is what gets signed
+    #[inline]
+    pub fn get_signed_data(&self) -> &[u8] {
+        &self.as_slice()[2 * std::mem::size_of::<RSASignature>()..]
+    }
+
+    // 16-digit uppercase hex, as usually displayed
+    #[inline]
+    pub fn get_program_id_str(&self) -> String {
+        format!("{:016X}", self.program_id)
+    }
+
+    // The program ID is the ID of the title the NCA belongs to (multi-program applications
+    // have one program ID per program, with the program index added to the application's ID)
+    #[inline]
+    pub fn get_title_id(&self) -> u64 {
+        self.program_id
+    }
+
+    #[inline]
+    pub fn has_rights_id(&self) -> bool {
+        self.rights_id != [0; 0x10]
+    }
+
+    #[inline]
+    pub fn get_rights_id_str(&self) -> String {
+        hex::encode_upper(self.rights_id)
+    }
 
+    // The actual master key revision (the raw key generation values are off by one)
     #[inline]
     pub fn get_key_generation(self) -> u8 {
         let base_key_gen = {
@@ -163,9 +488,50 @@ impl Header {
             base_key_gen
         }
     }
+
+    // Opposite of get_key_generation, the old field only going up to 2 like in official NCAs
+    pub fn set_key_generation(&mut self, key_generation: u8) {
+        let raw_key_generation = match key_generation {
+            0 => 0,
+            key_generation => key_generation.saturating_add(1),
+        };
+        self.key_generation_old = std::cmp::min(raw_key_generation, 2);
+        self.key_generation = match raw_key_generation > 2 {
+            true => raw_key_generation,
+            false => 0,
+        };
+    }
+
+    // Firmware versions which introduced (and used) each master key revision
+    pub fn get_key_generation_name(self) -> &'static str {
+        match self.get_key_generation() {
+            0 => "1.0.0-2.3.0",
+            1 => "3.0.0",
+            2 => "3.0.1-3.0.2",
+            3 => "4.0.0-4.1.0",
+            4 => "5.0.0-5.1.0",
+            5 => "6.0.0-6.1.0",
+            6 => "6.2.0",
+            7 => "7.0.0-8.0.1",
+            8 => "8.1.0-8.1.1",
+            9 => "9.0.0-9.0.1",
+            10 => "9.1.0-12.0.3",
+            11 => "12.1.0",
+            12 => "13.0.0-13.2.1",
+            13 => "14.0.0-14.1.2",
+            14 => "15.0.0-15.0.1",
+            15 => "16.0.0-16.1.0",
+            16 => "17.0.0-17.0.1",
+            17 => "18.0.0-18.1.0",
+            18 => "19.0.0-19.0.1",
+            19 => "20.0.0+",
+            _ => "unknown",
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum FileSystemType {
     RomFs,
@@ -173,6 +539,7 @@ pub enum FileSystemType {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum HashType {
     Auto = 0,
@@ -181,15 +548,56 @@ pub enum HashType {
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize))]
 #[repr(u8)]
 pub enum EncryptionType {
     Auto,
     None,
+    // Despite the name, old sections with this type are AES-XTS encrypted (0x200-byte sectors
+    // numbered from the section start, with the key area XTS key)
     AesCtrOld,
     AesCtr,
     AesCtrEx,
 }
 
+impl_try_from_u8!(FileSystemType, "filesystem type" {
+    0 => RomFs,
+    1 => PartitionFs,
+});
+
+impl_try_from_u8!(HashType, "hash type" {
+    0 => Auto,
+    2 => HierarchicalSha256,
+    3 => HierarchicalIntegrity,
+});
+
+impl_try_from_u8!(EncryptionType, "encryption type" {
+    0 => Auto,
+    1 => None,
+    2 => AesCtrOld,
+    3 => AesCtr,
+    4 => AesCtrEx,
+});
+
+impl_display_names!(FileSystemType {
+    RomFs => "romfs",
+    PartitionFs => "pfs0",
+});
+
+impl_display_names!(HashType {
+    Auto => "auto",
+    HierarchicalSha256 => "hierarchical_sha256",
+    HierarchicalIntegrity => "hierarchical_integrity",
+});
+
+impl_display_names!(EncryptionType {
+    Auto => "auto",
+    None => "none",
+    AesCtrOld => "aes_ctr_old",
+    AesCtr => "aes_ctr",
+    AesCtrEx => "aes_ctr_ex",
+});
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct HierarchicalSha256 {
@@ -208,6 +616,39 @@ pub struct HierarchicalSha256 {
     reserved_6: [u8; 0x10],
 }
 
+// Offsets are relative to the start of the section
+impl HierarchicalSha256 {
+    #[inline]
+    pub fn get_block_size(&self) -> u32 {
+        self.block_size
+    }
+
+    #[inline]
+    pub fn get_hash_table_hash(&self) -> &[u8; 0x20] {
+        &self.hash_table_hash.hash
+    }
+
+    #[inline]
+    pub fn get_hash_table_offset(&self) -> u64 {
+        self.hash_table_offset
+    }
+
+    #[inline]
+    pub fn get_hash_table_size(&self) -> usize {
+        self.hash_table_size
+    }
+
+    #[inline]
+    pub fn get_pfs0_offset(&self) -> u64 {
+        self.pfs0_offset
+    }
+
+    #[inline]
+    pub fn get_pfs0_size(&self) -> usize {
+        self.pfs0_size
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct HierarchicalIntegrityLevelInfo {
@@ -231,6 +672,24 @@ pub struct HierarchicalIntegrity {
 
 impl HierarchicalIntegrity {
     pub const MAGIC: u32 = u32::from_le_bytes(*b"IVFC");
+
+    // From the first hash level to the data level (the actual RomFs). Out of range block sizes are
+    // returned as 0, which IvfcReader rejects
+    pub fn get_ivfc_levels(&self) -> Vec<IvfcLevel> {
+        self.levels
+            .iter()
+            .map(|level| IvfcLevel {
+                offset: level.offset,
+                size: level.size as u64,
+                block_size: 1u64.checked_shl(level.block_size_log2).unwrap_or(0),
+            })
+            .collect()
+    }
+
+    #[inline]
+    pub fn get_master_hash(&self) -> &[u8; 0x20] {
+        &self.hash.hash
+    }
 }
 
 #[derive(Copy, Clone)]
@@ -246,9 +705,39 @@ pub struct BucketRelocationInfo {
     offset: u64,
     size: usize,
     magic: u32,
-    unk_1: u32,
-    unk_2: i32,
-    unk_3: u32,
+    version: u32,
+    entry_count: i32,
+    reserved: u32,
+}
+
+// The offset is relative to the start of the section
+impl BucketRelocationInfo {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"BKTR");
+
+    #[inline]
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn get_magic(&self) -> u32 {
+        self.magic
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> u32 {
+        self.version
+    }
+
+    #[inline]
+    pub fn get_entry_count(&self) -> i32 {
+        self.entry_count
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
@@ -258,6 +747,18 @@ pub struct PatchInfo {
     info_2: BucketRelocationInfo,
 }
 
+impl PatchInfo {
+    #[inline]
+    pub fn get_relocation_info(&self) -> &BucketRelocationInfo {
+        &self.info
+    }
+
+    #[inline]
+    pub fn get_subsection_info(&self) -> &BucketRelocationInfo {
+        &self.info_2
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct BucketInfo {
@@ -266,6 +767,24 @@ pub struct BucketInfo {
     header: [u8; 0x10],
 }
 
+// The offset is relative to the section's physical offset, see SparseInfo
+impl BucketInfo {
+    #[inline]
+    pub fn get_offset(&self) -> u64 {
+        self.offset
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    #[inline]
+    pub fn get_header(&self) -> &[u8; 0x10] {
+        &self.header
+    }
+}
+
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 #[repr(C)]
 pub struct SparseInfo {
@@ -294,123 +813,778 @@ pub struct FileSystemHeader {
     reserved_5: [u8; 0x8],
 }
 
-pub struct NCA {
-    reader: Shared<dyn ReadSeek>,
-    dec_key_area: KeyArea,
-    dec_title_key: Option<[u8; 0x10]>,
-    pub header: Header,
-    pub fs_headers: Vec<FileSystemHeader>,
-}
+impl FileSystemHeader {
+    pub const SIZE: usize = 0x200;
 
-impl NCA {
-    pub fn new(
-        reader: Shared<dyn ReadSeek>,
-        keyset: &Keyset,
-        title_key: Option<[u8; 0x10]>,
-    ) -> Result<Self> {
-        let cipher_1 = Aes128::new_varkey(&keyset.header_key[..0x10]).unwrap();
-        let cipher_2 = Aes128::new_varkey(&keyset.header_key[0x10..]).unwrap();
-        let xts = Xts128::new(cipher_1, cipher_2);
+    // Same as Header::from_bytes, the hash/patch/sparse info being plain integers any bytes are valid
+    pub fn from_bytes(data: &[u8; Self::SIZE]) -> Result<Self> {
+        FileSystemType::try_from(data[0x2])?;
+        HashType::try_from(data[0x3])?;
+        EncryptionType::try_from(data[0x4])?;
 
-        let mut header: Header = unsafe { std::mem::zeroed() };
-        let header_buf = unsafe {
-            std::slice::from_raw_parts_mut(
-                &mut header as *mut _ as *mut u8,
-                std::mem::size_of::<Header>(),
-            )
-        };
-        reader.lock().unwrap().read_exact(header_buf)?;
-        xts.decrypt_area(header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+        Ok(unsafe { std::ptr::read_unaligned(data.as_ptr() as *const Self) })
+    }
 
-        if header.magic != Header::MAGIC {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Invalid NCA magic (only NCA3 is supported for now)",
-            ));
+    pub fn as_slice(&self) -> &[u8] {
+        unsafe {
+            std::slice::from_raw_parts(self as *const _ as *const u8, std::mem::size_of::<Self>())
         }
+    }
 
-        let mut fs_headers: [FileSystemHeader; MAX_FILESYSTEM_COUNT] =
-            [unsafe { std::mem::zeroed() }; MAX_FILESYSTEM_COUNT];
-        let fs_headers_buf = unsafe {
-            std::slice::from_raw_parts_mut(
-                fs_headers.as_mut_ptr() as *mut u8,
-                std::mem::size_of::<FileSystemHeader>() * fs_headers.len(),
-            )
-        };
-        reader.lock().unwrap().read_exact(fs_headers_buf)?;
-        xts.decrypt_area(fs_headers_buf, SECTOR_SIZE, 2, get_nintendo_tweak);
+    #[inline]
+    pub fn get_version(&self) -> u16 {
+        self.version
+    }
 
-        let key_gen = header.get_key_generation();
-        let key_area_keys = match header.key_area_encryption_key_index {
-            KeyAreaEncryptionKeyIndex::Application => &keyset.key_area_keys_application,
-            KeyAreaEncryptionKeyIndex::Ocean => &keyset.key_area_keys_ocean,
-            KeyAreaEncryptionKeyIndex::System => &keyset.key_area_keys_system,
-        };
-        if key_gen as usize >= key_area_keys.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, format!("Key area key of kind {:?} (key_area_key_*_*) not present for key generation {}", header.key_area_encryption_key_index, key_gen)));
-        }
-        let key_area_key = &key_area_keys[key_gen as usize];
+    #[inline]
+    pub fn get_fs_type(&self) -> FileSystemType {
+        self.fs_type
+    }
 
-        let mut dec_key_area = KeyArea::empty();
-        let mut dec_title_key: Option<[u8; 0x10]> = None;
+    // As stored, see get_actual_hash_type
+    #[inline]
+    pub fn get_hash_type(&self) -> HashType {
+        self.hash_type
+    }
 
-        if header.rights_id != [0; 0x10] {
-            if let Some(mut enc_title_key) = title_key {
-                if key_gen as usize >= keyset.title_key_encryption_keys.len() {
-                    return Err(Error::new(ErrorKind::InvalidInput, format!("Title key encryption key (titlekek_*) not present for key generation {}", key_gen)));
-                }
+    #[inline]
+    pub fn get_encryption_type(&self) -> EncryptionType {
+        self.encryption_type
+    }
 
-                let title_key_encryption_key = keyset.title_key_encryption_keys[key_gen as usize];
-                let title_key_ecb_iv = [0; 0x10];
-                let title_key_ecb =
-                    Ecb::<Aes128, NoPadding>::new_var(&title_key_encryption_key, &title_key_ecb_iv)
-                        .unwrap();
-                dec_title_key = Some(
-                    title_key_ecb
-                        .decrypt(&mut enc_title_key)
-                        .unwrap()
-                        .try_into()
-                        .unwrap(),
-                );
-            } else {
-                return Err(Error::new(
-                    ErrorKind::InvalidInput,
-                    "NCA requires title key to be decrypted and none was supplied".to_string(),
-                ));
-            }
-        } else {
-            let dec_key_area_ecb_iv = get_nintendo_tweak(0);
-            let dec_key_area_ecb =
-                Ecb::<Aes128, NoPadding>::new_var(key_area_key, &dec_key_area_ecb_iv).unwrap();
-            dec_key_area = KeyArea::from_slice(
-                dec_key_area_ecb
-                    .decrypt(header.encrypted_key_area.as_mut_slice())
-                    .unwrap(),
-            );
+    // Upper half of the AES-CTR counter
+    #[inline]
+    pub fn get_ctr(&self) -> u64 {
+        self.ctr
+    }
+
+    // Only meaningful for AES-CTR-EX (patch) sections
+    #[inline]
+    pub fn get_patch_info(&self) -> &PatchInfo {
+        &self.patch_info
+    }
+
+    // Only meaningful for sparse sections, see is_sparse
+    #[inline]
+    pub fn get_sparse_info(&self) -> &SparseInfo {
+        &self.sparse_info
+    }
+
+    // Auto means the usual hash type of the filesystem type (HierarchicalSha256 for PFS0 sections,
+    // HierarchicalIntegrity for RomFs ones)
+    pub fn get_actual_hash_type(&self) -> HashType {
+        match (self.hash_type, self.fs_type) {
+            (HashType::Auto, FileSystemType::PartitionFs) => HashType::HierarchicalSha256,
+            (HashType::Auto, FileSystemType::RomFs) => HashType::HierarchicalIntegrity,
+            (hash_type, _) => hash_type,
         }
+    }
 
-        let mut actual_fs_headers: Vec<FileSystemHeader> = Vec::new();
-        #[allow(clippy::needless_range_loop)]
-        for i in 0..MAX_FILESYSTEM_COUNT {
-            let fs_entry = header.fs_entries[i];
-            let fs_header = fs_headers[i];
+    // The hash info is a union, only the variant matching the hash type is returned
+    pub fn get_hierarchical_sha256(&self) -> Option<&HierarchicalSha256> {
+        match self.get_actual_hash_type() {
+            HashType::HierarchicalSha256 => Some(unsafe { &self.hash_info.hierarchical_sha256 }),
+            _ => None,
+        }
+    }
 
-            let fs_start_offset = fs_entry.start_offset as u64 * MEDIA_UNIT_SIZE as u64;
-            if fs_start_offset > 0 {
-                // Only save non-empty/present filesystem headers
-                actual_fs_headers.push(fs_header);
+    pub fn get_hierarchical_integrity(&self) -> Option<&HierarchicalIntegrity> {
+        match self.get_actual_hash_type() {
+            HashType::HierarchicalIntegrity => {
+                Some(unsafe { &self.hash_info.hierarchical_integrity })
             }
+            _ => None,
         }
+    }
 
-        Ok(Self {
+    #[inline]
+    pub fn is_sparse(&self) -> bool {
+        self.sparse_info.generation != 0
+    }
+
+    fn get_hierarchical_sha256_mut(&mut self) -> Option<&mut HierarchicalSha256> {
+        match self.get_actual_hash_type() {
+            HashType::HierarchicalSha256 => {
+                Some(unsafe { &mut self.hash_info.hierarchical_sha256 })
+            }
+            _ => None,
+        }
+    }
+
+    fn get_hierarchical_integrity_mut(&mut self) -> Option<&mut HierarchicalIntegrity> {
+        match self.get_actual_hash_type() {
+            HashType::HierarchicalIntegrity => {
+                Some(unsafe { &mut self.hash_info.hierarchical_integrity })
+            }
+            _ => None,
+        }
+    }
+
+    // For sections which can't be read (or rebuilt) without their hash info
+    fn missing_hash_info_error(&self, idx: usize) -> Error {
+        Error::InvalidData(format!(
+            "Section {} ({:?}) has an unexpected {:?} hash type",
+            idx, self.fs_type, self.hash_type
+        ))
+    }
+}
+
+// Where a section is (absolute offset and size within the NCA) and how it's stored
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct SectionInfo {
+    pub offset: u64,
+    pub size: u64,
+    pub fs_type: FileSystemType,
+    pub encryption_type: EncryptionType,
+    pub hash_type: HashType,
+}
+
+// Hash tree block which doesn't match its hash, the offset being relative to the level start. For
+// HierarchicalSha256 sections level 0 is the hash table and level 1 the PFS0 region, IVFC levels are
+// numbered like HierarchicalIntegrity::get_ivfc_levels does (the last one being the RomFs)
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct BadHashBlock {
+    pub level: usize,
+    pub offset: u64,
+}
+
+// Result of verifying a single section, see NCA::verify_section
+#[derive(Debug)]
+pub struct SectionVerify {
+    pub idx: usize,
+    pub fs_type: FileSystemType,
+    pub hash_type: HashType,
+    pub fs_header_hash_valid: bool,
+    // Hash tree (IVFC or HierarchicalSha256) check
+    pub result: Result<()>,
+    // Blocks which failed the hash tree check, if it could be done at all
+    pub bad_blocks: Vec<BadHashBlock>,
+}
+
+impl SectionVerify {
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.fs_header_hash_valid && self.result.is_ok()
+    }
+}
+
+fn validity_name(valid: bool) -> &'static str {
+    match valid {
+        true => "valid",
+        false => "invalid",
+    }
+}
+
+// One line per check, then one per bad block
+impl fmt::Display for SectionVerify {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "section {} ({}, {}): fs header hash {}, hash tree ",
+            self.idx,
+            self.fs_type,
+            self.hash_type,
+            validity_name(self.fs_header_hash_valid)
+        )?;
+        match &self.result {
+            Ok(()) => f.write_str("valid")?,
+            Err(err) => write!(f, "invalid ({})", err)?,
+        }
+        for bad_block in self.bad_blocks.iter() {
+            write!(
+                f,
+                "\n  bad block at level {}, offset 0x{:X}",
+                bad_block.level, bad_block.offset
+            )?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug)]
+pub struct VerifyReport {
+    // None when there's no public key for the header's signature key generation
+    pub header_signature_valid: Option<bool>,
+    // Only for Program NCAs whose main.npdm could be read
+    pub header_signature_2_valid: Option<bool>,
+    pub sections: Vec<SectionVerify>,
+}
+
+impl VerifyReport {
+    // The header signature isn't taken into account, since modified NCAs never have a valid one
+    #[inline]
+    pub fn sections_valid(&self) -> bool {
+        self.sections.iter().all(SectionVerify::is_valid)
+    }
+
+    #[inline]
+    pub fn is_valid(&self) -> bool {
+        self.header_signature_valid == Some(true)
+            && self.header_signature_2_valid != Some(false)
+            && self.sections_valid()
+    }
+}
+
+impl fmt::Display for VerifyReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "header signature: {}",
+            self.header_signature_valid
+                .map_or("not checked", validity_name)
+        )?;
+        write!(
+            f,
+            "header signature 2: {}",
+            self.header_signature_2_valid
+                .map_or("not checked", validity_name)
+        )?;
+        for section in self.sections.iter() {
+            write!(f, "\n{}", section)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct NCAOptions {
+    // Check each filesystem header against its SHA-256 hash in the NCA header when opening
+    pub verify_fs_header_hashes: bool,
+    // Check RomFs sections against their IVFC hash tree as they are read
+    pub verify_romfs_hashes: bool,
+    // Check PFS0 sections against their HierarchicalSha256 hash table when opening them
+    pub verify_pfs0_hashes: bool,
+    // Check PFS0 blocks against the hash table as they are read instead, only the hash table itself
+    // being checked when opening. Ignored if verify_pfs0_hashes is set
+    pub verify_pfs0_hashes_on_read: bool,
+}
+
+pub struct NCA {
+    reader: Shared<dyn ReadSeek>,
+    dec_key_area: KeyArea,
+    dec_title_key: Option<[u8; 0x10]>,
+    options: NCAOptions,
+    version: NCAVersion,
+    pub header: Header,
+    pub fs_headers: Vec<FileSystemHeader>,
+    // Header slot (index in fs_entries/fs_header_hashes) of each of fs_headers
+    section_slots: Vec<usize>,
+}
+
+impl NCA {
+    // Used with the header key for the headers, and with the key area XTS key for old sections
+    fn new_xts(key: &[u8; 0x20]) -> Result<Xts128<Aes128>> {
+        let cipher_1 = Aes128::new_varkey(&key[..0x10]).map_err(crypto_error)?;
+        let cipher_2 = Aes128::new_varkey(&key[0x10..]).map_err(crypto_error)?;
+        Ok(Xts128::new(cipher_1, cipher_2))
+    }
+
+    // Reads and decrypts the main header only, the filesystem headers (if needed) come right after it
+    fn read_main_header(
+        reader: &Shared<dyn ReadSeek>,
+        xts: &Xts128<Aes128>,
+        min_size: usize,
+    ) -> Result<(Header, NCAVersion, bool)> {
+        {
+            let mut reader = reader.lock_shared();
+            let start_offset = reader.stream_position()?;
+            let end_offset = reader.seek(SeekFrom::End(0))?;
+            reader.seek(SeekFrom::Start(start_offset))?;
+            let size = end_offset.saturating_sub(start_offset);
+            if size < min_size as u64 {
+                return Err(Error::UnexpectedEof(format!(
+                    "File too small to be an NCA (0x{:X} bytes, at least 0x{:X} needed)",
+                    size, min_size
+                )));
+            }
+        }
+
+        let mut header_buf = [0u8; Header::SIZE];
+        reader.lock_shared().read_exact(&mut header_buf)?;
+        let read_magic =
+            |header_buf: &[u8]| u32::from_le_bytes(header_buf[0x200..0x204].try_into().unwrap());
+        let is_plaintext = NCAVersion::from_magic(read_magic(&header_buf)).is_some();
+        if !is_plaintext {
+            xts.decrypt_area(&mut header_buf, SECTOR_SIZE, 0, get_nintendo_tweak);
+        }
+
+        let magic = read_magic(&header_buf);
+        let version = NCAVersion::from_magic(magic).ok_or_else(|| {
+            log::debug!(
+                "Invalid NCA magic {:#010x} (expected {:#010x})",
+                magic,
+                Header::MAGIC
+            );
+            Error::InvalidMagic {
+                expected: Header::MAGIC,
+                found: magic,
+            }
+        })?;
+        log::trace!(
+            "Valid NCA magic for {:?} (plaintext headers: {})",
+            version,
+            is_plaintext
+        );
+        let header = Header::from_bytes(&header_buf)?;
+
+        Ok((header, version, is_plaintext))
+    }
+
+    // Plaintext headers (like the ones written by decrypt_to) are detected and read as-is, the returned
+    // flag telling whether the headers were plaintext
+    pub(crate) fn read_headers(
+        reader: &Shared<dyn ReadSeek>,
+        keyset: &Keyset,
+    ) -> Result<(
+        Header,
+        [FileSystemHeader; MAX_FILESYSTEM_COUNT],
+        NCAVersion,
+        bool,
+    )> {
+        let xts = Self::new_xts(&keyset.header_key)?;
+        let (header, version, is_plaintext) = Self::read_main_header(reader, &xts, HEADERS_SIZE)?;
+
+        let mut fs_headers_buf = [0u8; FileSystemHeader::SIZE * MAX_FILESYSTEM_COUNT];
+        reader.lock_shared().read_exact(&mut fs_headers_buf)?;
+        if !is_plaintext {
+            match version {
+                NCAVersion::NCA0 => {
+                    let dec_key_area = Self::decrypt_key_area(&header, keyset)?;
+                    let key_area_xts = Self::new_xts(dec_key_area.get_aes_xts_key())?;
+                    version.decrypt_fs_headers(&key_area_xts, &mut fs_headers_buf)?
+                }
+                _ => version.decrypt_fs_headers(&xts, &mut fs_headers_buf)?,
+            }
+        }
+
+        let mut fs_headers: [FileSystemHeader; MAX_FILESYSTEM_COUNT] =
+            [unsafe { std::mem::zeroed() }; MAX_FILESYSTEM_COUNT];
+        for ((fs_header, fs_entry), fs_header_buf) in fs_headers
+            .iter_mut()
+            .zip(header.fs_entries.iter())
+            .zip(fs_headers_buf.chunks(FileSystemHeader::SIZE))
+        {
+            // Headers of absent sections are never used, so they are left zeroed
+            if fs_entry.start_offset != 0 {
+                *fs_header = FileSystemHeader::from_bytes(fs_header_buf.try_into().unwrap())?;
+            }
+        }
+
+        Ok((header, fs_headers, version, is_plaintext))
+    }
+
+    fn get_key_area_key<'k>(header: &Header, keyset: &'k Keyset) -> Result<&'k [u8; 0x10]> {
+        let key_gen = header.get_key_generation();
+        log::debug!(
+            "Using {:?} key area key index",
+            header.key_area_encryption_key_index
+        );
+
+        keyset
+            .get_key_area_keys(header.key_area_encryption_key_index)
+            .get(key_gen as usize)
+            .ok_or_else(|| {
+                Error::MissingKeys(keyset.missing_keys_for(
+                    key_gen,
+                    header.key_area_encryption_key_index,
+                    false,
+                ))
+            })
+    }
+
+    #[inline]
+    pub fn new(
+        reader: Shared<dyn ReadSeek>,
+        keyset: &Keyset,
+        title_key: Option<[u8; 0x10]>,
+    ) -> Result<Self> {
+        Self::new_with_options(reader, keyset, title_key, NCAOptions::default())
+    }
+
+    #[inline]
+    pub fn open(
+        path: impl AsRef<Path>,
+        keyset: &Keyset,
+        title_key: Option<[u8; 0x10]>,
+    ) -> Result<Self> {
+        Self::new(new_shared(File::open(path)?), keyset, title_key)
+    }
+
+    // For untrusted in-memory data: never panics, and sections are also checked to be inside of the
+    // data upfront
+    pub fn try_new(data: &[u8], keyset: &Keyset, title_key: Option<[u8; 0x10]>) -> Result<Self> {
+        let nca = Self::new(new_shared_bytes(data.to_vec()), keyset, title_key)?;
+        for idx in 0..nca.get_filesystem_count() {
+            let section_info = nca.get_section_info(idx).ok_or(Error::InvalidIndex(idx))?;
+            if section_info.offset + section_info.size > data.len() as u64 {
+                return Err(Error::InvalidData(format!(
+                    "Section {} is outside of the NCA data",
+                    idx
+                )));
+            }
+        }
+
+        Ok(nca)
+    }
+
+    // Fast path for scanning lots of NCAs by ID/type: only the main header is read and decrypted, no
+    // filesystem headers, key area or section data (so no key area keys are needed either)
+    pub fn header_only(reader: Shared<dyn ReadSeek>, keyset: &Keyset) -> Result<Header> {
+        let xts = Self::new_xts(&keyset.header_key)?;
+        let (header, _, _) = Self::read_main_header(&reader, &xts, Header::SIZE)?;
+        Ok(header)
+    }
+
+    // Resolves the title key (if the NCA needs one) from a title key database
+    pub fn new_with_title_keys(
+        reader: Shared<dyn ReadSeek>,
+        keyset: &Keyset,
+        title_keys: &TitleKeys,
+    ) -> Result<Self> {
+        let start_offset = reader.lock_shared().stream_position()?;
+        let (header, _, _, _) = Self::read_headers(&reader, keyset)?;
+        reader.lock_shared().seek(SeekFrom::Start(start_offset))?;
+
+        // Unlike new, a title key missing from the database is an error right away
+        let title_key = match header.has_rights_id() {
+            true => Some(
+                title_keys
+                    .get(&header.rights_id)
+                    .ok_or(Error::MissingTitleKey)?,
+            ),
+            false => None,
+        };
+        Self::new(reader, keyset, title_key)
+    }
+
+    // Only the headers are read asynchronously and parsed as usual, section data being read through
+    // the returned AsyncNCA
+    #[cfg(feature = "tokio")]
+    pub async fn new_async<R: crate::util::AsyncReadSeek>(
+        mut reader: R,
+        keyset: &Keyset,
+        title_key: Option<[u8; 0x10]>,
+    ) -> Result<AsyncNCA<R>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut headers_data = vec![0u8; HEADERS_SIZE];
+        reader.seek(SeekFrom::Start(0)).await?;
+        reader.read_exact(&mut headers_data).await?;
+
+        Ok(AsyncNCA {
+            reader,
+            nca: Self::new(new_shared_bytes(headers_data), keyset, title_key)?,
+        })
+    }
+
+    fn decrypt_key_area(header: &Header, keyset: &Keyset) -> Result<KeyArea> {
+        let key_area_key = Self::get_key_area_key(header, keyset)?;
+
+        // Decrypt a copy, the header itself must stay as-is for signature checks
+        let mut enc_key_area = header.encrypted_key_area;
+        let dec_key_area_ecb_iv = get_nintendo_tweak(0);
+        let dec_key_area_ecb =
+            Ecb::<Aes128, NoPadding>::new_var(key_area_key, &dec_key_area_ecb_iv)
+                .map_err(crypto_error)?;
+        Ok(KeyArea::from_slice(
+            dec_key_area_ecb
+                .decrypt(enc_key_area.as_mut_slice())
+                .map_err(crypto_error)?,
+        ))
+    }
+
+    pub fn new_with_options(
+        reader: Shared<dyn ReadSeek>,
+        keyset: &Keyset,
+        title_key: Option<[u8; 0x10]>,
+        options: NCAOptions,
+    ) -> Result<Self> {
+        let (header, fs_headers, version, is_plaintext) = Self::read_headers(&reader, keyset)?;
+
+        let key_gen = header.get_key_generation();
+        log::debug!(
+            "Opening NCA with key generation {} ({})",
+            key_gen,
+            header.get_key_generation_name()
+        );
+        let mut dec_key_area = KeyArea::empty();
+        let mut dec_title_key: Option<[u8; 0x10]> = None;
+
+        // NCA0 predates title key crypto, the key area is needed for the filesystem headers anyway
+        if header.has_rights_id() && (version != NCAVersion::NCA0) {
+            log::debug!(
+                "NCA has rights ID {}, using title key crypto",
+                header.get_rights_id_str()
+            );
+            if let Some(mut enc_title_key) = title_key {
+                if key_gen as usize >= keyset.title_key_encryption_keys.len() {
+                    return Err(Error::MissingKeys(keyset.missing_keys_for(
+                        key_gen,
+                        header.key_area_encryption_key_index,
+                        true,
+                    )));
+                }
+
+                let title_key_encryption_key = keyset.title_key_encryption_keys[key_gen as usize];
+                let title_key_ecb_iv = [0; 0x10];
+                let title_key_ecb =
+                    Ecb::<Aes128, NoPadding>::new_var(&title_key_encryption_key, &title_key_ecb_iv)
+                        .map_err(crypto_error)?;
+                title_key_ecb
+                    .decrypt(&mut enc_title_key)
+                    .map_err(crypto_error)?;
+                dec_title_key = Some(enc_title_key);
+            } else {
+                // Headers are still usable, only opening sections fails
+                log::debug!("No title key supplied, sections can't be decrypted");
+            }
+        } else if is_plaintext {
+            log::debug!("NCA headers are plaintext, using the stored key area as-is");
+            // Plaintext NCAs store the key area already decrypted
+            dec_key_area = header.encrypted_key_area;
+        } else {
+            dec_key_area = Self::decrypt_key_area(&header, keyset)?;
+        }
+
+        let mut actual_fs_headers: Vec<FileSystemHeader> = Vec::new();
+        let mut section_slots: Vec<usize> = Vec::new();
+        #[allow(clippy::needless_range_loop)]
+        for i in 0..MAX_FILESYSTEM_COUNT {
+            let fs_entry = header.fs_entries[i];
+            let fs_header = fs_headers[i];
+
+            let fs_start_offset = fs_entry.start_offset as u64 * MEDIA_UNIT_SIZE as u64;
+            if fs_start_offset > 0 {
+                if fs_entry.end_offset < fs_entry.start_offset {
+                    return Err(Error::InvalidData(format!(
+                        "Section {} ends before it starts",
+                        i
+                    )));
+                }
+                log::trace!(
+                    "Section {} at {:#x}: {:?} filesystem, {:?} encryption",
+                    i,
+                    fs_start_offset,
+                    fs_header.fs_type,
+                    fs_header.encryption_type
+                );
+                // Only save non-empty/present filesystem headers
+                actual_fs_headers.push(fs_header);
+                section_slots.push(i);
+            }
+        }
+
+        let nca = Self {
             reader,
             dec_key_area,
             dec_title_key,
+            options,
+            version,
             header,
             fs_headers: actual_fs_headers,
+            section_slots,
+        };
+
+        if nca.options.verify_fs_header_hashes {
+            nca.verify_fs_headers()?;
+        }
+
+        Ok(nca)
+    }
+
+    fn verify_fs_header(&self, idx: usize) -> bool {
+        let fs_header_hash = Sha256::digest(self.fs_headers[idx].as_slice());
+        fs_header_hash.as_slice() == self.header.fs_header_hashes[self.section_slots[idx]].hash
+    }
+
+    // Absent sections are skipped in fs_headers, so a section index only matches its slot in the
+    // header (fs_entries, fs_header_hashes) when every section before it is present
+    #[inline]
+    pub fn get_section_slot(&self, idx: usize) -> Option<usize> {
+        self.section_slots.get(idx).copied()
+    }
+
+    pub fn verify_fs_headers(&self) -> Result<()> {
+        for i in 0..self.fs_headers.len() {
+            if !self.verify_fs_header(i) {
+                return Err(Error::HashMismatch(format!("filesystem header {}", i)));
+            }
+        }
+
+        Ok(())
+    }
+
+    fn verify_section_hashes(&self, idx: usize) -> Result<Vec<BadHashBlock>> {
+        let fs_header = &self.fs_headers[idx];
+        let section_reader = self.open_shared_section_reader(idx)?;
+        match fs_header.get_actual_hash_type() {
+            HashType::HierarchicalSha256 => Self::find_bad_pfs0_blocks(
+                &mut *section_reader.lock_shared(),
+                fs_header.get_hierarchical_sha256().unwrap(),
+            ),
+            HashType::HierarchicalIntegrity => {
+                let hierarchical_integrity = fs_header.get_hierarchical_integrity().unwrap();
+                Self::find_bad_ivfc_blocks(
+                    &mut *section_reader.lock_shared(),
+                    &hierarchical_integrity.get_ivfc_levels(),
+                    hierarchical_integrity.get_master_hash(),
+                )
+            }
+            HashType::Auto => Err(Error::InvalidData(format!(
+                "Unknown hash type for section {}",
+                idx
+            ))),
+        }
+    }
+
+    // Checks everything which can be checked (header signature, filesystem header hashes and the hash
+    // tree of every section), reporting all failures instead of stopping at the first one
+    pub fn verify(&mut self) -> Result<VerifyReport> {
+        let header_signature_valid = match self.verify_header_signature() {
+            Err(Error::MissingKeys(_)) => None,
+            result => Some(result?),
+        };
+        let header_signature_2_valid = match self.header.cnt_type {
+            ContentType::Program => self.verify_header_signature_2().ok(),
+            _ => None,
+        };
+        let sections = (0..self.fs_headers.len())
+            .map(|idx| self.verify_section(idx))
+            .collect::<Result<Vec<_>>>()?;
+
+        Ok(VerifyReport {
+            header_signature_valid,
+            header_signature_2_valid,
+            sections,
+        })
+    }
+
+    // Checks the filesystem header hash and the whole hash tree of a section, reporting every block
+    // which doesn't match its hash. Hash trees which can't be checked at all are an error in `result`
+    pub fn verify_section(&self, idx: usize) -> Result<SectionVerify> {
+        let fs_header = self.fs_headers.get(idx).ok_or(Error::InvalidIndex(idx))?;
+        let (result, bad_blocks) = match self.verify_section_hashes(idx) {
+            Ok(bad_blocks) if bad_blocks.is_empty() => (Ok(()), bad_blocks),
+            Ok(bad_blocks) => (
+                Err(Error::HashMismatch(format!(
+                    "{} hash tree block(s) in section {}",
+                    bad_blocks.len(),
+                    idx
+                ))),
+                bad_blocks,
+            ),
+            Err(err) => (Err(err), Vec::new()),
+        };
+
+        Ok(SectionVerify {
+            idx,
+            fs_type: fs_header.fs_type,
+            hash_type: fs_header.hash_type,
+            fs_header_hash_valid: self.verify_fs_header(idx),
+            result,
+            bad_blocks,
         })
     }
 
+    // Checks against the built-in retail and dev public keys for the header's signature key
+    // generation, see verify_header_signature_with_keyset for the ones which aren't built-in
+    pub fn verify_header_signature(&self) -> Result<bool> {
+        let key_gen = self.header.header_1_signature_key_generation as usize;
+        let moduli: Vec<&[u8; 0x100]> = [HEADER_FIXED_KEY_MODULI, HEADER_FIXED_KEY_MODULI_DEV]
+            .iter()
+            .filter_map(|moduli| moduli.get(key_gen))
+            .collect();
+        if moduli.is_empty() {
+            return Err(Error::MissingKeys(vec![format!(
+                "nca_hdr_fixed_key_modulus_{:02x}",
+                key_gen
+            )]));
+        }
+
+        for modulus in moduli {
+            if self.verify_header_signature_with_modulus(modulus)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    // Uses the keyset's public key for the header's signature key generation (loading dev.keys checks
+    // against the dev keys), falling back to the built-in ones
+    pub fn verify_header_signature_with_keyset(&self, keyset: &Keyset) -> Result<bool> {
+        let key_gen = self.header.header_1_signature_key_generation as usize;
+        match keyset.header_fixed_key_moduli.get(key_gen) {
+            Some(modulus) if modulus.iter().any(|b| *b != 0) => {
+                self.verify_header_signature_with_modulus(modulus)
+            }
+            _ => self.verify_header_signature(),
+        }
+    }
+
+    #[inline]
+    pub fn verify_header_signature_with_modulus(&self, modulus: &[u8]) -> Result<bool> {
+        self.verify_signed_data(modulus, &self.header.header_rsa_sig_1)
+    }
+
+    // The second header signature of Program NCAs is made with the key in the ACID of the main.npdm
+    // in their ExeFs, so it's only meaningful along with the first signature and the ExeFs hashes,
+    // which are what makes that key trustworthy
+    pub fn verify_header_signature_2(&mut self) -> Result<bool> {
+        let npdm = self.open_npdm()?;
+        self.verify_header_signature_2_with_modulus(&npdm.acid.nca_signature_modulus)
+    }
+
+    #[inline]
+    pub fn verify_header_signature_2_with_modulus(&self, modulus: &[u8]) -> Result<bool> {
+        self.verify_signed_data(modulus, &self.header.header_rsa_sig_2)
+    }
+
+    // Mismatching signatures aren't an error, they just mean the header isn't properly signed
+    fn verify_signed_data(&self, modulus: &[u8], signature: &RSASignature) -> Result<bool> {
+        let public_key = RsaPublicKey::new(
+            BigUint::from_bytes_be(modulus),
+            BigUint::from(HEADER_FIXED_KEY_EXPONENT),
+        )
+        .map_err(|err| Error::InvalidInput(format!("Invalid header public key: {}", err)))?;
+
+        let signed_data_hash = Sha256::digest(self.header.get_signed_data());
+        Ok(public_key
+            .verify(
+                Pss::new_with_salt::<Sha256>(HEADER_SIGNATURE_SALT_LEN),
+                &signed_data_hash,
+                signature.as_slice(),
+            )
+            .is_ok())
+    }
+
+    // The first half of the SHA-256 of the whole NCA (exactly cnt_size bytes), also its file name
+    pub fn compute_content_id(&self) -> Result<[u8; 0x10]> {
+        let mut reader = self.reader.lock_shared();
+        reader.seek(SeekFrom::Start(0))?;
+
+        let hash = sha256_reader(&mut *reader, self.header.cnt_size as u64)?;
+        Ok(hash[..0x10].try_into().unwrap())
+    }
+
+    // Accepts either the plain hex content ID or a file name like "<content-id>.cnmt.nca"
+    pub fn verify_content_id(&self, expected: &str) -> Result<bool> {
+        let expected_hex = expected.split('.').next().unwrap_or_default();
+        let expected_content_id = <[u8; 0x10]>::from_hex(expected_hex)
+            .map_err(|_| Error::InvalidInput(format!("Invalid content ID: '{}'", expected)))?;
+
+        Ok(self.compute_content_id()? == expected_content_id)
+    }
+
+    #[inline]
+    pub fn get_version(&self) -> NCAVersion {
+        self.version
+    }
+
+    #[inline]
+    pub fn get_distribution_type(&self) -> DistributionType {
+        self.header.dist_type
+    }
+
     #[inline]
     pub fn get_filesystem_count(&self) -> usize {
         self.fs_headers.len()
@@ -424,106 +1598,1401 @@ impl NCA {
         }
     }
 
-    fn get_fs_offset(&self, idx: usize) -> u64 {
-        let fs_header = &self.fs_headers[idx];
-        let fs_entry = &self.header.fs_entries[idx];
-
-        if fs_header.sparse_info.generation != 0 {
-            todo!("Sparse section NCA support")
-        } else {
-            fs_entry.start_offset as u64 * MEDIA_UNIT_SIZE as u64
+    // Same, but NCAs opened without their title key have no key to give
+    fn get_section_key(&self) -> Result<[u8; 0x10]> {
+        match (self.dec_title_key, self.header.has_rights_id()) {
+            (Some(dec_title_key), _) => Ok(dec_title_key),
+            (None, true) => Err(Error::MissingTitleKey),
+            (None, false) => Ok(self.dec_key_area.aes_ctr_key),
         }
     }
 
     #[inline]
-    pub fn needs_title_key_crypto(&self) -> bool {
-        self.header.rights_id != [0; 0x10]
+    fn get_fs_offset(&self, idx: usize) -> u64 {
+        self.header.fs_entries[self.section_slots[idx]].start_offset as u64 * MEDIA_UNIT_SIZE as u64
     }
 
-    pub fn open_pfs0_filesystem(&mut self, idx: usize) -> Result<PFS0> {
-        if idx >= self.fs_headers.len() {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Invalid filesystem index",
-            ));
+    // Reader over the raw (still encrypted) section data at its NCA offsets, which for sparse
+    // sections goes through their table
+    fn open_raw_section_reader(&self, idx: usize) -> Result<Shared<dyn ReadSeek>> {
+        let fs_header = &self.fs_headers[idx];
+        if !fs_header.is_sparse() {
+            return Ok(self.reader.clone());
         }
 
-        let fs_header = &self.fs_headers[idx];
-        if fs_header.fs_type != FileSystemType::PartitionFs {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                format!(
-                    "Invalid filesystem type (actual type: {:?})",
-                    fs_header.fs_type
-                ),
-            ));
+        let sparse_info = &fs_header.sparse_info;
+        let bucket = &sparse_info.bucket;
+        if bucket.header[..0x4] != BucketRelocationInfo::MAGIC.to_le_bytes() {
+            return Err(Error::InvalidMagic {
+                expected: BucketRelocationInfo::MAGIC,
+                found: u32::from_le_bytes(bucket.header[..0x4].try_into().unwrap()),
+            });
         }
 
-        let fs_start_offset = self.get_fs_offset(idx);
+        let section_size = self.get_section_info(idx).unwrap().size;
+        let entry_count = i32::from_le_bytes(bucket.header[0x8..0xC].try_into().unwrap());
+        let sparse_table = match entry_count {
+            // Nothing stored at all, the whole section is zeros
+            0 => RelocationTable {
+                entries: vec![RelocationEntry {
+                    virtual_offset: 0,
+                    physical_offset: 0,
+                    is_patch: true,
+                }],
+                virtual_size: section_size,
+            },
+            _ => {
+                // The table is encrypted with the section counter, the generation replacing
+                // the lower half (unlike the section data, which uses the regular counter)
+                let table_offset = sparse_info
+                    .physical_offset
+                    .checked_add(bucket.offset)
+                    .ok_or_else(|| {
+                        Error::InvalidData(String::from("Invalid sparse table offset"))
+                    })?;
+                let table_reader = match fs_header.encryption_type {
+                    EncryptionType::AesCtr => new_shared(Aes128CtrReader::new(
+                        self.reader.clone(),
+                        0,
+                        (fs_header.ctr & 0xFFFFFFFF00000000)
+                            | ((sparse_info.generation as u64) << 16),
+                        self.get_section_key()?.to_vec(),
+                    )),
+                    EncryptionType::None => self.reader.clone(),
+                    enc_type => return Err(Error::UnsupportedEncryption(enc_type)),
+                };
+                let mut table_reader = table_reader.lock_shared();
+                table_reader.seek(SeekFrom::Start(table_offset))?;
+                RelocationTable::from_slice(&read_to_vec(&mut *table_reader, bucket.size as u64)?)?
+            }
+        };
+
+        Ok(new_shared(SparseReader::new(
+            self.reader.clone(),
+            sparse_info.physical_offset,
+            self.get_fs_offset(idx),
+            sparse_table,
+        )))
+    }
 
-        match fs_header.encryption_type {
-            EncryptionType::AesCtr => {
-                let pfs0_abs_offset = fs_start_offset
-                    + unsafe { fs_header.hash_info.hierarchical_sha256.pfs0_offset };
-                let dec_key = self.get_aes_ctr_decrypt_key();
-                let pfs0_reader = new_shared(Aes128CtrReader::new(
-                    self.reader.clone(),
-                    pfs0_abs_offset,
-                    fs_header.ctr,
-                    dec_key,
-                ));
+    // Headers as they were parsed (so after decryption), headers of absent sections being zeroed
+    pub fn get_decrypted_header_bytes(&self) -> [u8; HEADERS_SIZE] {
+        let mut headers_data = [0u8; HEADERS_SIZE];
+        headers_data[..Header::SIZE].copy_from_slice(self.header.as_slice());
 
-                PFS0::new(pfs0_reader)
+        // Only present sections are kept in fs_headers, in order
+        let mut fs_headers = self.fs_headers.iter();
+        for (idx, fs_entry) in self.header.fs_entries.iter().enumerate() {
+            if fs_entry.start_offset == 0 {
+                continue;
+            }
+            if let Some(fs_header) = fs_headers.next() {
+                let fs_header_offset = Header::SIZE + idx * FileSystemHeader::SIZE;
+                headers_data[fs_header_offset..fs_header_offset + FileSystemHeader::SIZE]
+                    .copy_from_slice(fs_header.as_slice());
             }
-            enc_type => todo!("Unsupported crypto type: {:?}", enc_type),
         }
+
+        headers_data
     }
 
-    pub fn open_romfs_filesystem(&mut self, idx: usize) -> Result<RomFs> {
-        if idx >= self.fs_headers.len() {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
-                "Invalid filesystem index",
-            ));
-        }
+    // Empty if the NCA uses title key crypto
+    #[inline]
+    pub fn get_decrypted_key_area(&self) -> &KeyArea {
+        &self.dec_key_area
+    }
 
-        let fs_header = &self.fs_headers[idx];
+    #[inline]
+    pub fn get_decrypted_title_key(&self) -> Option<[u8; 0x10]> {
+        self.dec_title_key
+    }
+
+    // Index, filesystem type and encryption of every section, to pick the right opener upfront
+    pub fn sections(&self) -> impl Iterator<Item = (usize, FileSystemType, EncryptionType)> + '_ {
+        self.fs_headers
+            .iter()
+            .enumerate()
+            .map(|(idx, fs_header)| (idx, fs_header.fs_type, fs_header.encryption_type))
+    }
+
+    pub fn get_section_info(&self, idx: usize) -> Option<SectionInfo> {
+        let fs_header = self.fs_headers.get(idx)?;
+        let fs_entry = &self.header.fs_entries[self.section_slots[idx]];
+
+        Some(SectionInfo {
+            offset: fs_entry.start_offset as u64 * MEDIA_UNIT_SIZE as u64,
+            size: (fs_entry.end_offset - fs_entry.start_offset) as u64 * MEDIA_UNIT_SIZE as u64,
+            fs_type: fs_header.fs_type,
+            encryption_type: fs_header.encryption_type,
+            hash_type: fs_header.hash_type,
+        })
+    }
+
+    #[inline]
+    pub fn needs_title_key_crypto(&self) -> bool {
+        self.header.has_rights_id()
+    }
+
+    // The hash table (checked to cover the whole PFS0 region) and whether it matches its hash
+    fn read_pfs0_hash_table(
+        mut section_reader: impl ReadSeek,
+        hash_info: &HierarchicalSha256,
+    ) -> Result<(Vec<u8>, bool)> {
+        section_reader.seek(SeekFrom::Start(hash_info.hash_table_offset))?;
+        let hash_table = read_to_vec(&mut section_reader, hash_info.hash_table_size as u64)?;
+
+        let block_size = hash_info.block_size as usize;
+        if block_size == 0 {
+            return Err(Error::InvalidData(String::from(
+                "Invalid PFS0 hash block size",
+            )));
+        }
+        let block_count = hash_info.pfs0_size.div_ceil(block_size);
+        if !matches!(block_count.checked_mul(0x20), Some(size) if size <= hash_table.len()) {
+            return Err(Error::InvalidData(String::from(
+                "PFS0 hash table is too small for the PFS0 region",
+            )));
+        }
+
+        let is_valid = Sha256::digest(&hash_table).as_slice() == hash_info.hash_table_hash.hash;
+        Ok((hash_table, is_valid))
+    }
+
+    // Every PFS0 block which doesn't match the hash table, or only the hash table itself if it
+    // doesn't match its hash (as the block hashes can't be trusted then)
+    fn find_bad_pfs0_blocks(
+        mut section_reader: impl ReadSeek,
+        hash_info: &HierarchicalSha256,
+    ) -> Result<Vec<BadHashBlock>> {
+        let (hash_table, is_valid) = Self::read_pfs0_hash_table(&mut section_reader, hash_info)?;
+        if !is_valid {
+            return Ok(vec![BadHashBlock {
+                level: 0,
+                offset: 0,
+            }]);
+        }
+
+        // The last block is hashed as-is, without any padding. Blocks are streamed into the hasher,
+        // the block size being up to the header too
+        let block_size = hash_info.block_size as usize;
+        let block_count = hash_info.pfs0_size.div_ceil(block_size);
+        let mut bad_blocks: Vec<BadHashBlock> = Vec::new();
+        for (block_idx, block_hash) in hash_table.chunks(0x20).take(block_count).enumerate() {
+            let block_offset = block_idx * block_size;
+            let block_data_size =
+                std::cmp::min(block_size, hash_info.pfs0_size - block_offset) as u64;
+            let data_offset = hash_info
+                .pfs0_offset
+                .checked_add(block_offset as u64)
+                .ok_or_else(|| Error::InvalidData(String::from("Invalid PFS0 offset")))?;
+            section_reader.seek(SeekFrom::Start(data_offset))?;
+            let mut hasher = Sha256::new();
+            let hashed_size = std::io::copy(
+                &mut (&mut section_reader).take(block_data_size),
+                &mut hasher,
+            )?;
+            if hashed_size != block_data_size {
+                return Err(Error::UnexpectedEof(format!(
+                    "PFS0 block at offset 0x{:X}",
+                    block_offset
+                )));
+            }
+            if hasher.finalize().as_slice() != block_hash {
+                bad_blocks.push(BadHashBlock {
+                    level: 1,
+                    offset: block_offset as u64,
+                });
+            }
+        }
+
+        Ok(bad_blocks)
+    }
+
+    // Every block of every IVFC level which doesn't match its hash, from the master hash down. Blocks
+    // whose hash is in a bad block of the level above aren't checked, as that hash can't be trusted.
+    // Hash levels are kept in memory while going down, the RomFs level is only streamed
+    fn find_bad_ivfc_blocks(
+        mut section_reader: impl ReadSeek,
+        levels: &[IvfcLevel],
+        master_hash: &[u8; 0x20],
+    ) -> Result<Vec<BadHashBlock>> {
+        IvfcReader::check_levels(levels)?;
+
+        let mut bad_blocks: Vec<BadHashBlock> = Vec::new();
+        // The level above (as its data, block size and bad block offsets), the master hash acting as
+        // a single-block level
+        let mut parent_data = master_hash.to_vec();
+        let mut parent_block_size = IvfcReader::HASH_SIZE;
+        let mut parent_bad_offsets: HashSet<u64> = HashSet::new();
+        for (level_idx, level) in levels.iter().enumerate() {
+            let is_hash_level = level_idx + 1 < levels.len();
+            let mut level_data: Vec<u8> = Vec::new();
+            let mut level_bad_offsets: HashSet<u64> = HashSet::new();
+            let mut block = Vec::new();
+            section_reader.seek(SeekFrom::Start(level.offset))?;
+
+            let block_count = level.size.div_ceil(level.block_size);
+            for block_idx in 0..block_count {
+                let block_offset = block_idx * level.block_size;
+                let block_size = std::cmp::min(level.block_size, level.size - block_offset);
+                block.clear();
+                (&mut section_reader)
+                    .take(block_size)
+                    .read_to_end(&mut block)?;
+                if block.len() as u64 != block_size {
+                    return Err(Error::UnexpectedEof(format!(
+                        "IVFC level {} block at offset 0x{:X}",
+                        level_idx, block_offset
+                    )));
+                }
+                if is_hash_level {
+                    level_data.extend_from_slice(&block);
+                }
+
+                let hash_offset = block_idx * IvfcReader::HASH_SIZE;
+                let parent_block_offset = hash_offset - hash_offset % parent_block_size;
+                if parent_bad_offsets.contains(&parent_block_offset) {
+                    continue;
+                }
+
+                // Partial blocks are hashed as if they were padded with zeros
+                let mut hasher = Sha256::new();
+                hasher.update(&block);
+                hasher.update(vec![0u8; (level.block_size - block_size) as usize]);
+                let block_hash = hasher.finalize();
+                let expected_hash = parent_data
+                    .get(hash_offset as usize..)
+                    .and_then(|hash| hash.get(..IvfcReader::HASH_SIZE as usize));
+                if expected_hash != Some(block_hash.as_slice()) {
+                    bad_blocks.push(BadHashBlock {
+                        level: level_idx,
+                        offset: block_offset,
+                    });
+                    level_bad_offsets.insert(block_offset);
+                }
+            }
+
+            parent_data = level_data;
+            parent_block_size = level.block_size;
+            parent_bad_offsets = level_bad_offsets;
+        }
+
+        Ok(bad_blocks)
+    }
+
+    fn verify_pfs0_hashes(
+        section_reader: impl ReadSeek,
+        hash_info: &HierarchicalSha256,
+    ) -> Result<()> {
+        match Self::find_bad_pfs0_blocks(section_reader, hash_info)?.first() {
+            Some(BadHashBlock { level: 0, .. }) => {
+                Err(Error::HashMismatch(String::from("PFS0 hash table")))
+            }
+            Some(bad_block) => Err(Error::HashMismatch(format!(
+                "PFS0 block at offset 0x{:X}",
+                bad_block.offset
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    pub fn open_pfs0_filesystem(&mut self, idx: usize) -> Result<PFS0> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::InvalidIndex(idx));
+        }
+
+        let fs_header = &self.fs_headers[idx];
+        if fs_header.fs_type != FileSystemType::PartitionFs {
+            return Err(Error::InvalidFileSystemType(fs_header.fs_type));
+        }
+
+        let hash_info = fs_header
+            .get_hierarchical_sha256()
+            .ok_or_else(|| fs_header.missing_hash_info_error(idx))?;
+        let section_reader = self.open_shared_section_reader(idx)?;
+        if self.options.verify_pfs0_hashes {
+            Self::verify_pfs0_hashes(&mut *section_reader.lock_shared(), hash_info)?;
+        } else if self.options.verify_pfs0_hashes_on_read {
+            let (hash_table, is_valid) =
+                Self::read_pfs0_hash_table(&mut *section_reader.lock_shared(), hash_info)?;
+            if !is_valid {
+                return Err(Error::HashMismatch(String::from("PFS0 hash table")));
+            }
+            return PFS0::new(new_shared(HierarchicalSha256Reader::new(
+                section_reader,
+                *hash_info,
+                hash_table,
+            )));
+        }
+
+        let pfs0_reader = SubReader::new(
+            section_reader,
+            hash_info.pfs0_offset,
+            hash_info.pfs0_size as u64,
+        );
+        PFS0::new(new_shared(pfs0_reader))
+    }
+
+    // Plain AES-CTR reader over a whole section (offsets relative to the section start)
+    fn open_ctr_section_reader(&self, idx: usize) -> Result<Shared<dyn ReadSeek>> {
+        Ok(new_shared(Aes128CtrReader::new(
+            self.open_raw_section_reader(idx)?,
+            self.get_fs_offset(idx),
+            self.fs_headers[idx].ctr,
+            self.get_section_key()?.to_vec(),
+        )))
+    }
+
+    // AES-XTS reader over a whole section with the key area XTS key, sectors being numbered from the
+    // section start for AesCtrOld sections, and from the end of the main header for NCA0 ones
+    fn open_xts_section_reader(&self, idx: usize) -> Result<Shared<dyn ReadSeek>> {
+        let fs_offset = self.get_fs_offset(idx);
+        let first_sector_index = match self.version {
+            NCAVersion::NCA0 => {
+                fs_offset.checked_sub(Header::SIZE as u64).ok_or_else(|| {
+                    Error::InvalidData(format!("NCA0 section {} overlaps the header", idx))
+                })? / SECTOR_SIZE as u64
+            }
+            _ => 0,
+        };
+
+        Ok(new_shared(Aes128XtsReader::new(
+            self.open_raw_section_reader(idx)?,
+            fs_offset,
+            self.get_section_info(idx).unwrap().size,
+            Self::new_xts(self.dec_key_area.get_aes_xts_key())?,
+            SECTOR_SIZE,
+            first_sector_index,
+        )))
+    }
+
+    // Decrypted raw section data, hash layers included, for dumping sections or parsing them some other
+    // way. Patch sections only make sense over their base, see open_romfs_filesystem_with_base
+    pub fn open_section_reader(&self, idx: usize) -> Result<SubReader> {
+        let section_info = self.get_section_info(idx).ok_or(Error::InvalidIndex(idx))?;
+        if section_info.encryption_type == EncryptionType::AesCtrEx {
+            return Err(Error::UnsupportedEncryption(EncryptionType::AesCtrEx));
+        }
+
+        Ok(SubReader::new(
+            self.open_shared_section_reader(idx)?,
+            0,
+            section_info.size,
+        ))
+    }
+
+    // Reader over a whole plain AES-CTR, AES-XTS (old or NCA0) or unencrypted section
+    fn open_shared_section_reader(&self, idx: usize) -> Result<Shared<dyn ReadSeek>> {
+        let encryption_type = self.fs_headers[idx].encryption_type;
+        // Every encrypted NCA0 section uses AES-XTS, whatever its encryption type says
+        if (self.version == NCAVersion::NCA0) && (encryption_type != EncryptionType::None) {
+            return self.open_xts_section_reader(idx);
+        }
+
+        match encryption_type {
+            EncryptionType::AesCtr => self.open_ctr_section_reader(idx),
+            EncryptionType::AesCtrOld => self.open_xts_section_reader(idx),
+            EncryptionType::None => {
+                let section_info = self.get_section_info(idx).unwrap();
+                Ok(new_shared(SubReader::new(
+                    self.open_raw_section_reader(idx)?,
+                    self.get_fs_offset(idx),
+                    section_info.size,
+                )))
+            }
+            enc_type => Err(Error::UnsupportedEncryption(enc_type)),
+        }
+    }
+
+    fn read_patch_table(
+        section_reader: &Shared<dyn ReadSeek>,
+        info: &BucketRelocationInfo,
+    ) -> Result<Vec<u8>> {
+        if info.magic != BucketRelocationInfo::MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: BucketRelocationInfo::MAGIC,
+                found: info.magic,
+            });
+        }
+
+        let mut section_reader = section_reader.lock_shared();
+        section_reader.seek(SeekFrom::Start(info.offset))?;
+        read_to_vec(&mut *section_reader, info.size as u64)
+    }
+
+    fn open_patched_section_reader(
+        &self,
+        idx: usize,
+        base_nca: Option<&NCA>,
+    ) -> Result<Shared<dyn ReadSeek>> {
+        let base_reader = match base_nca {
+            Some(base_nca) => {
+                let base_idx = base_nca
+                    .fs_headers
+                    .iter()
+                    .position(|fs_header| fs_header.fs_type == FileSystemType::RomFs)
+                    .ok_or_else(|| Error::NotFound(String::from("Base NCA RomFs filesystem")))?;
+                Some(base_nca.open_shared_section_reader(base_idx)?)
+            }
+            None => None,
+        };
+
+        // The BKTR tables themselves are encrypted with the plain section counter
+        let fs_header = &self.fs_headers[idx];
+        if fs_header.is_sparse() {
+            return Err(Error::UnsupportedFeature(format!(
+                "Sparse patch NCA section (section {})",
+                idx
+            )));
+        }
+        let section_reader = self.open_ctr_section_reader(idx)?;
+        let relocation_table = RelocationTable::from_slice(&Self::read_patch_table(
+            &section_reader,
+            &fs_header.patch_info.info,
+        )?)?;
+        let subsection_table = SubsectionTable::from_slice(&Self::read_patch_table(
+            &section_reader,
+            &fs_header.patch_info.info_2,
+        )?)?;
+
+        Ok(new_shared(BktrReader::new(
+            base_reader,
+            self.reader.clone(),
+            self.get_fs_offset(idx),
+            fs_header.ctr,
+            self.get_section_key()?.to_vec(),
+            relocation_table,
+            subsection_table,
+        )))
+    }
+
+    #[inline]
+    pub fn open_romfs_filesystem(&mut self, idx: usize) -> Result<RomFs> {
+        self.open_romfs_filesystem_with_base(idx, None)
+    }
+
+    pub fn open_filesystem(&mut self, idx: usize) -> Result<Box<dyn Filesystem>> {
+        let fs_header = self.fs_headers.get(idx).ok_or(Error::InvalidIndex(idx))?;
+
+        match fs_header.fs_type {
+            FileSystemType::PartitionFs => Ok(Box::new(self.open_pfs0_filesystem(idx)?)),
+            FileSystemType::RomFs => Ok(Box::new(self.open_romfs_filesystem(idx)?)),
+        }
+    }
+
+    pub const NPDM_FILE_NAME: &'static str = "main.npdm";
+
+    // The ExeFs of Program NCAs (the NSOs like rtld, main, subsdk* and sdk, plus main.npdm) is their
+    // first section, but it's looked up by its main.npdm like the logo
+    pub fn open_exefs(&mut self) -> Result<PFS0> {
+        self.check_program("an ExeFs")?;
+
+        for idx in 0..self.fs_headers.len() {
+            if self.fs_headers[idx].fs_type != FileSystemType::PartitionFs {
+                continue;
+            }
+
+            let pfs0 = self.open_pfs0_filesystem(idx)?;
+            if pfs0.find_file(Self::NPDM_FILE_NAME).is_some() {
+                return Ok(pfs0);
+            }
+        }
+
+        Err(Error::NotFound(String::from("NCA ExeFs section")))
+    }
+
+    fn check_program(&self, what: &str) -> Result<()> {
+        match self.header.cnt_type {
+            ContentType::Program => Ok(()),
+            cnt_type => Err(Error::InvalidInput(format!(
+                "{:?} NCAs don't have {}",
+                cnt_type, what
+            ))),
+        }
+    }
+
+    fn read_pfs0_file(mut pfs0: PFS0, name: &str) -> Result<Vec<u8>> {
+        let mut file_reader = pfs0.get_file_reader_by_name(name)?;
+        let file_size = file_reader.get_size();
+        read_to_vec(&mut file_reader, file_size)
+    }
+
+    #[inline]
+    pub fn read_exefs_file(&mut self, name: &str) -> Result<Vec<u8>> {
+        Self::read_pfs0_file(self.open_exefs()?, name)
+    }
+
+    #[inline]
+    pub fn open_npdm(&mut self) -> Result<NPDM> {
+        let mut exefs = self.open_exefs()?;
+        NPDM::from(exefs.get_file_reader_by_name(Self::NPDM_FILE_NAME)?)
+    }
+
+    pub const LOGO_FILE_NAMES: [&'static str; 2] = ["NintendoLogo.png", "StartupMovie.gif"];
+
+    // Program NCAs usually have the logo as their last PFS0 section (after the ExeFs and the RomFs), so
+    // it's looked up from the end, by its files
+    pub fn open_logo(&mut self) -> Result<PFS0> {
+        self.check_program("a logo")?;
+        for idx in (0..self.fs_headers.len()).rev() {
+            if self.fs_headers[idx].fs_type != FileSystemType::PartitionFs {
+                continue;
+            }
+
+            let pfs0 = self.open_pfs0_filesystem(idx)?;
+            if Self::LOGO_FILE_NAMES
+                .iter()
+                .any(|file_name| pfs0.find_file(file_name).is_some())
+            {
+                return Ok(pfs0);
+            }
+        }
+
+        Err(Error::NotFound(String::from("NCA logo section")))
+    }
+
+    // Like NintendoLogo.png or StartupMovie.gif, see LOGO_FILE_NAMES
+    #[inline]
+    pub fn read_logo_file(&mut self, name: &str) -> Result<Vec<u8>> {
+        Self::read_pfs0_file(self.open_logo()?, name)
+    }
+
+    // The fully patched RomFs of an update NCA section, whatever the patch didn't replace being read
+    // from the base NCA (its RomFs section). Unlike open_romfs_filesystem_with_base, the section has
+    // to be a patch one
+    pub fn open_patched_romfs(&mut self, idx: usize, base_nca: &NCA) -> Result<RomFs> {
+        let fs_header = self.fs_headers.get(idx).ok_or(Error::InvalidIndex(idx))?;
+        if fs_header.encryption_type != EncryptionType::AesCtrEx {
+            return Err(Error::InvalidInput(format!(
+                "NCA section {} isn't a patch section",
+                idx
+            )));
+        }
+
+        self.open_romfs_filesystem_with_base(idx, Some(base_nca))
+    }
+
+    // Patch (update) sections need the base NCA they apply to, the data they don't replace
+    // coming from it
+    pub fn open_romfs_filesystem_with_base(
+        &mut self,
+        idx: usize,
+        base_nca: Option<&NCA>,
+    ) -> Result<RomFs> {
+        if idx >= self.fs_headers.len() {
+            return Err(Error::InvalidIndex(idx));
+        }
+
+        let fs_header = &self.fs_headers[idx];
         if fs_header.fs_type != FileSystemType::RomFs {
-            return Err(Error::new(
-                ErrorKind::InvalidInput,
+            return Err(Error::InvalidFileSystemType(fs_header.fs_type));
+        }
+        // NCA0 RomFs sections use an older RomFs format
+        if self.version == NCAVersion::NCA0 {
+            return Err(Error::UnsupportedFeature(format!(
+                "NCA0 RomFs section (section {})",
+                idx
+            )));
+        }
+
+        let hierarchical_integrity = *fs_header
+            .get_hierarchical_integrity()
+            .ok_or_else(|| fs_header.missing_hash_info_error(idx))?;
+        let section_reader = match fs_header.encryption_type {
+            EncryptionType::AesCtr | EncryptionType::AesCtrOld | EncryptionType::None => {
+                self.open_shared_section_reader(idx)?
+            }
+            // Without the base NCA, only what the patch changed can be read
+            EncryptionType::AesCtrEx => self.open_patched_section_reader(idx, base_nca)?,
+            enc_type => return Err(Error::UnsupportedEncryption(enc_type)),
+        };
+
+        let ivfc_levels = hierarchical_integrity.get_ivfc_levels();
+
+        if self.options.verify_romfs_hashes {
+            return RomFs::new_verified(
+                section_reader,
+                &ivfc_levels,
+                hierarchical_integrity.hash.hash,
+            );
+        }
+
+        let romfs_level = ivfc_levels.last().unwrap();
+        let romfs_reader = SubReader::new(section_reader, romfs_level.offset, romfs_level.size);
+        RomFs::new(new_shared(romfs_reader))
+    }
+
+    fn copy_range(
+        reader: &Shared<dyn ReadSeek>,
+        offset: u64,
+        size: u64,
+        out: &mut impl Write,
+    ) -> Result<()> {
+        let mut reader = reader.lock_shared();
+        reader.seek(SeekFrom::Start(offset))?;
+        if io::copy(&mut (&mut *reader).take(size), out)? != size {
+            return Err(Error::UnexpectedEof(format!(
+                "Could not read NCA data at offset 0x{:X}",
+                offset
+            )));
+        }
+
+        Ok(())
+    }
+
+    // Writes a fully decrypted (hactool's "plaintext") NCA: plaintext headers, with the decrypted
+    // key area (holding the title key if any, the rights ID being cleared) and every section marked
+    // as unencrypted, which can be opened again with any keyset. Header signatures won't be valid
+    // anymore, and patch (AES-CTR-EX) sections can't be decrypted on their own
+    pub fn decrypt_to(&mut self, mut out: impl Write) -> Result<()> {
+        let mut header = self.header;
+        header.rights_id = [0; 0x10];
+        header.encrypted_key_area = self.dec_key_area;
+        if let Some(dec_title_key) = self.dec_title_key {
+            header.encrypted_key_area.aes_ctr_key = dec_title_key;
+        }
+
+        let mut fs_headers: [FileSystemHeader; MAX_FILESYSTEM_COUNT] =
+            [unsafe { std::mem::zeroed() }; MAX_FILESYSTEM_COUNT];
+        let mut sections: Vec<(SectionInfo, Shared<dyn ReadSeek>)> = Vec::new();
+        for (idx, fs_header) in self.fs_headers.iter().enumerate() {
+            sections.push((
+                self.get_section_info(idx).unwrap(),
+                self.open_shared_section_reader(idx)?,
+            ));
+
+            // Sparse sections are written out whole, so they become regular ones
+            let slot = self.section_slots[idx];
+            fs_headers[slot] = *fs_header;
+            fs_headers[slot].encryption_type = EncryptionType::None;
+            fs_headers[slot].sparse_info = unsafe { std::mem::zeroed() };
+            header.fs_header_hashes[slot].hash = Sha256::digest(fs_headers[slot].as_slice()).into();
+        }
+        sections.sort_by_key(|(section_info, _)| section_info.offset);
+
+        out.write_all(header.as_slice())?;
+        for fs_header in fs_headers.iter() {
+            out.write_all(fs_header.as_slice())?;
+        }
+
+        // Anything outside the sections is copied as-is
+        let mut offset = (std::mem::size_of::<Header>()
+            + std::mem::size_of::<FileSystemHeader>() * MAX_FILESYSTEM_COUNT)
+            as u64;
+        let cnt_size = self.header.cnt_size as u64;
+        let overlap_error = || {
+            Error::InvalidData(String::from(
+                "NCA sections overlap or exceed the content size",
+            ))
+        };
+        for (section_info, section_reader) in sections.iter() {
+            if section_info.offset < offset {
+                return Err(overlap_error());
+            }
+            if section_info.offset > offset {
+                Self::copy_range(&self.reader, offset, section_info.offset - offset, &mut out)?;
+            }
+            Self::copy_range(section_reader, 0, section_info.size, &mut out)?;
+            offset = section_info.offset + section_info.size;
+        }
+        if offset > cnt_size {
+            return Err(overlap_error());
+        }
+        if cnt_size > offset {
+            Self::copy_range(&self.reader, offset, cnt_size - offset, &mut out)?;
+        }
+
+        Ok(())
+    }
+}
+
+// NCA over an async reader, see NCA::new_async
+#[cfg(feature = "tokio")]
+pub struct AsyncNCA<R: crate::util::AsyncReadSeek> {
+    reader: R,
+    // Only backed by the headers, used for everything but reading section data
+    nca: NCA,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: crate::util::AsyncReadSeek> AsyncNCA<R> {
+    #[inline]
+    pub fn get_nca(&self) -> &NCA {
+        &self.nca
+    }
+
+    #[inline]
+    pub fn get_filesystem_count(&self) -> usize {
+        self.nca.get_filesystem_count()
+    }
+
+    // Takes over the reader, since the PFS0 reads through it
+    pub async fn open_pfs0_filesystem(
+        self,
+        idx: usize,
+    ) -> Result<crate::pfs0::AsyncPFS0<crate::util::AsyncAes128CtrReader<R>>> {
+        let fs_header = self
+            .nca
+            .fs_headers
+            .get(idx)
+            .ok_or(Error::InvalidIndex(idx))?;
+        if fs_header.fs_type != FileSystemType::PartitionFs {
+            return Err(Error::InvalidFileSystemType(fs_header.fs_type));
+        }
+        if fs_header.encryption_type != EncryptionType::AesCtr {
+            return Err(Error::UnsupportedEncryption(fs_header.encryption_type));
+        }
+        if fs_header.is_sparse() {
+            return Err(Error::UnsupportedFeature(format!(
+                "Sparse NCA section (section {})",
+                idx
+            )));
+        }
+
+        let hash_info = fs_header
+            .get_hierarchical_sha256()
+            .ok_or_else(|| fs_header.missing_hash_info_error(idx))?;
+        let pfs0_offset = self.nca.get_fs_offset(idx) + hash_info.pfs0_offset;
+        let pfs0_reader = crate::util::AsyncAes128CtrReader::new(
+            self.reader,
+            pfs0_offset,
+            fs_header.ctr,
+            self.nca.get_section_key()?,
+        );
+        PFS0::new_async(pfs0_reader).await
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+// Reads the PFS0 region of a HierarchicalSha256 section, checking every block it touches against the
+// (already checked) hash table. Only the last block read is kept around
+struct HierarchicalSha256Reader {
+    inner: Shared<dyn ReadSeek>,
+    hash_info: HierarchicalSha256,
+    hash_table: Vec<u8>,
+    cached_block: Option<(u64, Vec<u8>)>,
+    offset: u64,
+}
+
+impl HierarchicalSha256Reader {
+    fn new(
+        inner: Shared<dyn ReadSeek>,
+        hash_info: HierarchicalSha256,
+        hash_table: Vec<u8>,
+    ) -> Self {
+        Self {
+            inner,
+            hash_info,
+            hash_table,
+            cached_block: None,
+            offset: 0,
+        }
+    }
+
+    fn read_verified_block(&mut self, block_idx: u64) -> io::Result<()> {
+        if self.cached_block.as_ref().map(|(idx, _)| *idx) == Some(block_idx) {
+            return Ok(());
+        }
+
+        let block_size = self.hash_info.block_size as u64;
+        let block_offset = block_idx * block_size;
+        let block_data_size =
+            std::cmp::min(block_size, self.hash_info.pfs0_size as u64 - block_offset);
+        let mut block = Vec::new();
+        {
+            let mut inner = self.inner.lock_shared();
+            let data_offset = self
+                .hash_info
+                .pfs0_offset
+                .checked_add(block_offset)
+                .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "Invalid PFS0 offset"))?;
+            inner.seek(SeekFrom::Start(data_offset))?;
+            (&mut *inner)
+                .take(block_data_size)
+                .read_to_end(&mut block)?;
+        }
+        if block.len() as u64 != block_data_size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("Could not read PFS0 block at offset 0x{:X}", block_offset),
+            ));
+        }
+
+        let hash_offset = block_idx as usize * 0x20;
+        if Sha256::digest(&block).as_slice() != &self.hash_table[hash_offset..hash_offset + 0x20] {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
                 format!(
-                    "Invalid filesystem type (actual type: {:?})",
-                    fs_header.fs_type
+                    "PFS0 hash mismatch for block at offset 0x{:X}",
+                    block_offset
                 ),
             ));
         }
 
-        let fs_start_offset = self.get_fs_offset(idx);
-
-        match fs_header.encryption_type {
-            EncryptionType::AesCtr => {
-                let romfs_offset = fs_start_offset
-                    + unsafe {
-                        fs_header
-                            .hash_info
-                            .hierarchical_integrity
-                            .levels
-                            .last()
-                            .as_ref()
-                            .unwrap()
-                            .offset
+        self.cached_block = Some((block_idx, block));
+        Ok(())
+    }
+}
+
+impl Read for HierarchicalSha256Reader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let pfs0_size = self.hash_info.pfs0_size as u64;
+        let block_size = self.hash_info.block_size as u64;
+
+        let mut read_size = 0;
+        while (read_size < buf.len()) && (self.offset < pfs0_size) {
+            let block_idx = self.offset / block_size;
+            self.read_verified_block(block_idx)?;
+            let block = &self.cached_block.as_ref().unwrap().1;
+
+            let block_read_offset = (self.offset % block_size) as usize;
+            let copy_size = std::cmp::min(buf.len() - read_size, block.len() - block_read_offset);
+            buf[read_size..read_size + copy_size]
+                .copy_from_slice(&block[block_read_offset..block_read_offset + copy_size]);
+
+            read_size += copy_size;
+            self.offset += copy_size as u64;
+        }
+
+        Ok(read_size)
+    }
+}
+
+impl Seek for HierarchicalSha256Reader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.offset.checked_add_signed(offset),
+            SeekFrom::End(offset) => (self.hash_info.pfs0_size as u64).checked_add_signed(offset),
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(new_offset)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of PFS0",
+            )),
+        }
+    }
+}
+
+enum SectionSource<'a> {
+    // Section of the NCA being rebuilt, copied as-is
+    Original(usize),
+    // PFS0 or plain RomFs image, like PFS0Builder/RomFsBuilder write, hashed when written
+    Image(Box<dyn Read + 'a>),
+}
+
+// Rebuilds an NCA, optionally replacing the data of some sections, or creates a new NCA3 from PFS0 and
+// RomFs images. Images get their hash trees built (with the original block sizes for replaced
+// sections), and everything is encrypted with AES-CTR. Sections are laid out one after another, and
+// header signatures can't be computed so they won't be valid
+pub struct NCABuilder<'a> {
+    nca: Option<&'a NCA>,
+    keyset: &'a Keyset,
+    header: Header,
+    fs_headers: Vec<FileSystemHeader>,
+    // Header slot of each of fs_headers, see NCA::get_section_slot
+    section_slots: Vec<usize>,
+    dec_key_area: KeyArea,
+    section_sources: Vec<SectionSource<'a>>,
+}
+
+impl<'a> NCABuilder<'a> {
+    const COPY_CHUNK_SIZE: usize = 0x100000;
+    // Block sizes for new sections, as used by official ExeFs and RomFs sections
+    pub const PFS0_HASH_BLOCK_SIZE: u32 = 0x10000;
+    pub const IVFC_BLOCK_SIZE_LOG2: u32 = 14;
+
+    pub fn new(nca: &'a NCA, keyset: &'a Keyset) -> Self {
+        Self {
+            nca: Some(nca),
+            keyset,
+            header: nca.header,
+            fs_headers: nca.fs_headers.clone(),
+            section_slots: nca.section_slots.clone(),
+            dec_key_area: nca.dec_key_area,
+            section_sources: (0..nca.fs_headers.len())
+                .map(SectionSource::Original)
+                .collect(),
+        }
+    }
+
+    // Empty NCA3 whose sections are encrypted with `section_key`, stored in the key area with the
+    // keyset's application key area key for `key_generation` (the actual master key revision)
+    pub fn create(
+        keyset: &'a Keyset,
+        cnt_type: ContentType,
+        program_id: u64,
+        key_generation: u8,
+        section_key: [u8; 0x10],
+    ) -> Self {
+        let mut header: Header = unsafe { std::mem::zeroed() };
+        header.magic = NCAVersion::NCA3_MAGIC;
+        header.dist_type = DistributionType::System;
+        header.cnt_type = cnt_type;
+        header.key_area_encryption_key_index = KeyAreaEncryptionKeyIndex::Application;
+        header.program_id = program_id;
+        header.set_key_generation(key_generation);
+
+        let mut dec_key_area = KeyArea::empty();
+        dec_key_area.aes_ctr_key = section_key;
+
+        Self {
+            nca: None,
+            keyset,
+            header,
+            fs_headers: Vec::new(),
+            section_slots: Vec::new(),
+            dec_key_area,
+            section_sources: Vec::new(),
+        }
+    }
+
+    fn check_key_area_used(&self) -> Result<()> {
+        match self.header.has_rights_id() {
+            true => Err(Error::InvalidInput(String::from(
+                "Title key NCAs don't use their key area, their ticket has to be re-encrypted instead",
+            ))),
+            false => Ok(()),
+        }
+    }
+
+    // The key area is encrypted again with the key area key for the new key generation (the actual
+    // master key revision), e.g. so that consoles on older firmwares can decrypt the NCA
+    pub fn set_key_generation(&mut self, key_generation: u8) -> Result<&mut Self> {
+        self.check_key_area_used()?;
+        self.header.set_key_generation(key_generation);
+        Ok(self)
+    }
+
+    pub fn set_key_area_key_index(
+        &mut self,
+        index: KeyAreaEncryptionKeyIndex,
+    ) -> Result<&mut Self> {
+        self.check_key_area_used()?;
+        self.header.key_area_encryption_key_index = index;
+        Ok(self)
+    }
+
+    pub fn replace_section(&mut self, idx: usize, data: impl Read + 'a) -> Result<&mut Self> {
+        let section_source = self
+            .section_sources
+            .get_mut(idx)
+            .ok_or(Error::InvalidIndex(idx))?;
+        *section_source = SectionSource::Image(Box::new(data));
+        Ok(self)
+    }
+
+    // Adds an AES-CTR section after the existing ones, from a PFS0 (hashed with HierarchicalSha256)
+    // or RomFs (hashed with IVFC) image
+    pub fn add_section(
+        &mut self,
+        fs_type: FileSystemType,
+        data: impl Read + 'a,
+    ) -> Result<&mut Self> {
+        // The section goes in the slot after the last used one
+        let slot = self.section_slots.last().map_or(0, |slot| slot + 1);
+        if slot >= MAX_FILESYSTEM_COUNT {
+            return Err(Error::InvalidInput(format!(
+                "NCAs can't have more than {} sections",
+                MAX_FILESYSTEM_COUNT
+            )));
+        }
+
+        let mut fs_header: FileSystemHeader = unsafe { std::mem::zeroed() };
+        fs_header.version = 2;
+        fs_header.fs_type = fs_type;
+        fs_header.encryption_type = EncryptionType::AesCtr;
+        // Upper half of the counter, which only has to differ between sections
+        fs_header.ctr = slot as u64;
+        match fs_type {
+            FileSystemType::PartitionFs => {
+                fs_header.hash_type = HashType::HierarchicalSha256;
+                let hash_info = unsafe { &mut fs_header.hash_info.hierarchical_sha256 };
+                hash_info.block_size = Self::PFS0_HASH_BLOCK_SIZE;
+                // Layer count
+                hash_info.unk_2 = 2;
+            }
+            FileSystemType::RomFs => {
+                fs_header.hash_type = HashType::HierarchicalIntegrity;
+                let hash_info = unsafe { &mut fs_header.hash_info.hierarchical_integrity };
+                hash_info.magic = HierarchicalIntegrity::MAGIC;
+                hash_info.magic_num = 0x20000;
+                hash_info.maybe_master_hash_size = 0x20;
+                // Level count, the master hash included
+                hash_info.unk_7 = hash_info.levels.len() as u32 + 1;
+                for level in hash_info.levels.iter_mut() {
+                    level.block_size_log2 = Self::IVFC_BLOCK_SIZE_LOG2;
+                }
+            }
+        }
+
+        self.fs_headers.push(fs_header);
+        self.section_slots.push(slot);
+        self.section_sources
+            .push(SectionSource::Image(Box::new(data)));
+        Ok(self)
+    }
+
+    fn get_block_size(block_size_log2: u32) -> Result<usize> {
+        1usize
+            .checked_shl(block_size_log2)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid IVFC block size")))
+    }
+
+    // Partial blocks are hashed as if they were padded with zeros
+    fn hash_ivfc_blocks(level: &[u8], block_size: usize) -> Vec<u8> {
+        let mut hashes = Vec::with_capacity(level.len().div_ceil(block_size) * 0x20);
+        for block in level.chunks(block_size) {
+            let mut hasher = Sha256::new();
+            hasher.update(block);
+            hasher.update(vec![0u8; block_size - block.len()]);
+            hashes.extend_from_slice(&hasher.finalize());
+        }
+        hashes
+    }
+
+    fn build_ivfc_section(
+        hash_info: &mut HierarchicalIntegrity,
+        romfs_data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let mut levels = vec![romfs_data];
+        for level_idx in (1..hash_info.levels.len()).rev() {
+            let block_size = Self::get_block_size(hash_info.levels[level_idx].block_size_log2)?;
+            levels.insert(0, Self::hash_ivfc_blocks(&levels[0], block_size));
+        }
+
+        // The master hash only covers a single block of the first level
+        let master_block_size = Self::get_block_size(hash_info.levels[0].block_size_log2)?;
+        if levels[0].len() > master_block_size {
+            return Err(Error::InvalidInput(String::from(
+                "RomFs data is too big for the section's IVFC levels",
+            )));
+        }
+        hash_info
+            .hash
+            .hash
+            .copy_from_slice(&Self::hash_ivfc_blocks(&levels[0], master_block_size)[..0x20]);
+
+        let mut section = Vec::new();
+        for (level_info, level) in hash_info.levels.iter_mut().zip(levels) {
+            let block_size = Self::get_block_size(level_info.block_size_log2)?;
+            level_info.offset = section.len() as u64;
+            level_info.size = level.len();
+            section.extend_from_slice(&level);
+            section.resize(align_up(section.len(), block_size), 0);
+        }
+        Ok(section)
+    }
+
+    // The hash table comes first, then the PFS0 (last block hashed as-is)
+    fn build_sha256_section(
+        hash_info: &mut HierarchicalSha256,
+        pfs0_data: Vec<u8>,
+    ) -> Result<Vec<u8>> {
+        let block_size = hash_info.block_size as usize;
+        if block_size == 0 {
+            return Err(Error::InvalidData(String::from(
+                "Invalid PFS0 hash block size",
+            )));
+        }
+
+        let mut section: Vec<u8> = pfs0_data
+            .chunks(block_size)
+            .flat_map(Sha256::digest)
+            .collect();
+        hash_info.hash_table_hash.hash = Sha256::digest(&section).into();
+        hash_info.hash_table_offset = 0;
+        hash_info.hash_table_size = section.len();
+        section.resize(align_up(section.len(), MEDIA_UNIT_SIZE), 0);
+        hash_info.pfs0_offset = section.len() as u64;
+        hash_info.pfs0_size = pfs0_data.len();
+        section.extend_from_slice(&pfs0_data);
+        Ok(section)
+    }
+
+    pub fn write_to(mut self, mut out: impl Write) -> Result<()> {
+        let version = self.nca.map_or(NCAVersion::NCA3, |nca| nca.version);
+        // Sections are always encrypted back with AES-CTR, which NCA0 doesn't use
+        if version == NCAVersion::NCA0 {
+            return Err(Error::UnsupportedVersion(version));
+        }
+
+        // Every slot is rebuilt, so that absent sections don't keep stale entries or hashes
+        let mut header = self.header;
+        header.fs_entries = unsafe { std::mem::zeroed() };
+        header.fs_header_hashes = unsafe { std::mem::zeroed() };
+        let mut fs_headers: [FileSystemHeader; MAX_FILESYSTEM_COUNT] =
+            [unsafe { std::mem::zeroed() }; MAX_FILESYSTEM_COUNT];
+
+        // (Plaintext section reader, data size, offset within the NCA, aligned size)
+        let mut sections: Vec<(Shared<dyn ReadSeek>, u64, u64, u64)> = Vec::new();
+        let mut offset = (std::mem::size_of::<Header>()
+            + std::mem::size_of::<FileSystemHeader>() * MAX_FILESYSTEM_COUNT)
+            as u64;
+        // Slots only grow with section indices, so sections are laid out in slot order
+        for (idx, &slot) in self.section_slots.iter().enumerate() {
+            let fs_header = &mut fs_headers[slot];
+            *fs_header = self.fs_headers[idx];
+            fs_header.sparse_info = unsafe { std::mem::zeroed() };
+            match fs_header.encryption_type {
+                EncryptionType::AesCtr | EncryptionType::None => {}
+                enc_type => return Err(Error::UnsupportedEncryption(enc_type)),
+            }
+
+            let section_source =
+                std::mem::replace(&mut self.section_sources[idx], SectionSource::Original(idx));
+            let (section_reader, section_size) = match (section_source, self.nca) {
+                (SectionSource::Image(mut section_source), _) => {
+                    let mut data = Vec::new();
+                    section_source.read_to_end(&mut data)?;
+                    let missing_hash_info_error = fs_header.missing_hash_info_error(idx);
+                    let section = match fs_header.fs_type {
+                        FileSystemType::PartitionFs => Self::build_sha256_section(
+                            fs_header
+                                .get_hierarchical_sha256_mut()
+                                .ok_or(missing_hash_info_error)?,
+                            data,
+                        )?,
+                        FileSystemType::RomFs => Self::build_ivfc_section(
+                            fs_header
+                                .get_hierarchical_integrity_mut()
+                                .ok_or(missing_hash_info_error)?,
+                            data,
+                        )?,
                     };
-                let dec_key = self.get_aes_ctr_decrypt_key();
-                let romfs_reader = new_shared(Aes128CtrReader::new(
-                    self.reader.clone(),
-                    romfs_offset,
-                    fs_header.ctr,
-                    dec_key,
-                ));
+                    let section_size = section.len() as u64;
+                    (new_shared_bytes(section), section_size)
+                }
+                (SectionSource::Original(idx), Some(nca)) => (
+                    nca.open_shared_section_reader(idx)?,
+                    nca.get_section_info(idx).unwrap().size,
+                ),
+                (SectionSource::Original(idx), None) => return Err(Error::InvalidIndex(idx)),
+            };
+
+            let aligned_size = align_up(section_size as usize, MEDIA_UNIT_SIZE) as u64;
+            header.fs_entries[slot].start_offset = (offset / MEDIA_UNIT_SIZE as u64) as u32;
+            header.fs_entries[slot].end_offset =
+                ((offset + aligned_size) / MEDIA_UNIT_SIZE as u64) as u32;
+            header.fs_header_hashes[slot].hash = Sha256::digest(fs_header.as_slice()).into();
+            sections.push((section_reader, section_size, offset, aligned_size));
+            offset += aligned_size;
+        }
+        header.cnt_size = offset as usize;
+
+        // Title key NCAs don't use the key area, so it's kept as-is
+        if !header.has_rights_id() {
+            let key_area_key = NCA::get_key_area_key(&header, self.keyset)?;
+            let mut key_area = self.dec_key_area;
+            Ecb::<Aes128, NoPadding>::new_var(key_area_key, &get_nintendo_tweak(0))
+                .map_err(crypto_error)?
+                .encrypt(key_area.as_mut_slice(), std::mem::size_of::<KeyArea>())
+                .map_err(crypto_error)?;
+            header.encrypted_key_area = key_area;
+        }
+
+        let xts = NCA::new_xts(&self.keyset.header_key)?;
+        let mut header_data = header.as_slice().to_vec();
+        xts.encrypt_area(&mut header_data, SECTOR_SIZE, 0, get_nintendo_tweak);
+        out.write_all(&header_data)?;
+        let mut fs_headers_data: Vec<u8> = fs_headers
+            .iter()
+            .flat_map(|fs_header| fs_header.as_slice().to_vec())
+            .collect();
+        version.encrypt_fs_headers(&xts, &mut fs_headers_data)?;
+        out.write_all(&fs_headers_data)?;
+
+        let key = match self.nca {
+            Some(nca) => nca.get_section_key()?,
+            None => *self.dec_key_area.get_aes_ctr_key(),
+        };
+        let mut buf = vec![0u8; Self::COPY_CHUNK_SIZE];
+        for (&slot, (section_reader, section_size, section_offset, aligned_size)) in
+            self.section_slots.iter().zip(sections)
+        {
+            let mut section_reader = section_reader.lock_shared();
+            section_reader.seek(SeekFrom::Start(0))?;
+
+            let mut done_size = 0u64;
+            while done_size < aligned_size {
+                let chunk_size = std::cmp::min(buf.len() as u64, aligned_size - done_size);
+                let chunk = &mut buf[..chunk_size as usize];
+                let data_size = std::cmp::min(chunk_size, section_size.saturating_sub(done_size));
+                section_reader.read_exact(&mut chunk[..data_size as usize])?;
+                chunk[data_size as usize..].fill(0);
+
+                if fs_headers[slot].encryption_type == EncryptionType::AesCtr {
+                    aes128_ctr_crypt(
+                        chunk,
+                        section_offset + done_size,
+                        fs_headers[slot].ctr,
+                        &key,
+                    );
+                }
+                out.write_all(chunk)?;
+                done_size += chunk_size;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+// Byte arrays (hashes, IDs...) are rendered as hex strings, and the hash info depends on the hash type
+#[cfg(feature = "serde")]
+mod ser {
+    use super::*;
+    use serde::ser::{Serialize, SerializeStruct, Serializer};
+
+    struct Hex<'a>(&'a [u8]);
+
+    impl Serialize for Hex<'_> {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            serializer.serialize_str(&hex::encode(self.0))
+        }
+    }
+
+    impl Serialize for Sha256Hash {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            Hex(&self.hash).serialize(serializer)
+        }
+    }
+
+    impl Serialize for RSASignature {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            Hex(self.as_slice()).serialize(serializer)
+        }
+    }
+
+    impl Serialize for SdkAddonVersion {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("SdkAddonVersion", 4)?;
+            state.serialize_field("major", &self.major)?;
+            state.serialize_field("minor", &self.minor)?;
+            state.serialize_field("micro", &self.micro)?;
+            state.serialize_field("unk", &self.unk)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for FileSystemEntry {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("FileSystemEntry", 2)?;
+            state.serialize_field("start_offset", &self.start_offset)?;
+            state.serialize_field("end_offset", &self.end_offset)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for Header {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("Header", 16)?;
+            state.serialize_field("header_rsa_sig_1", &self.header_rsa_sig_1)?;
+            state.serialize_field("header_rsa_sig_2", &self.header_rsa_sig_2)?;
+            state.serialize_field("magic", &String::from_utf8_lossy(&self.magic.to_le_bytes()))?;
+            state.serialize_field("dist_type", &self.dist_type)?;
+            state.serialize_field("cnt_type", &self.cnt_type)?;
+            state.serialize_field("key_generation_old", &self.key_generation_old)?;
+            state.serialize_field(
+                "key_area_encryption_key_index",
+                &self.key_area_encryption_key_index,
+            )?;
+            state.serialize_field("cnt_size", &self.cnt_size)?;
+            state.serialize_field("program_id", &self.get_program_id_str())?;
+            state.serialize_field("cnt_idx", &self.cnt_idx)?;
+            state.serialize_field("sdk_addon_ver", &self.sdk_addon_ver)?;
+            state.serialize_field("key_generation", &self.key_generation)?;
+            state.serialize_field(
+                "header_1_signature_key_generation",
+                &self.header_1_signature_key_generation,
+            )?;
+            state.serialize_field("rights_id", &Hex(&self.rights_id))?;
+            state.serialize_field("fs_entries", &self.fs_entries)?;
+            state.serialize_field("fs_header_hashes", &self.fs_header_hashes)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for HierarchicalSha256 {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("HierarchicalSha256", 6)?;
+            state.serialize_field("hash_table_hash", &self.hash_table_hash)?;
+            state.serialize_field("block_size", &self.block_size)?;
+            state.serialize_field("hash_table_offset", &self.hash_table_offset)?;
+            state.serialize_field("hash_table_size", &self.hash_table_size)?;
+            state.serialize_field("pfs0_offset", &self.pfs0_offset)?;
+            state.serialize_field("pfs0_size", &self.pfs0_size)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for HierarchicalIntegrityLevelInfo {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("HierarchicalIntegrityLevelInfo", 3)?;
+            state.serialize_field("offset", &self.offset)?;
+            state.serialize_field("size", &self.size)?;
+            state.serialize_field("block_size_log2", &self.block_size_log2)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for HierarchicalIntegrity {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("HierarchicalIntegrity", 3)?;
+            state.serialize_field("magic_num", &self.magic_num)?;
+            state.serialize_field("levels", &self.levels)?;
+            state.serialize_field("hash", &self.hash)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for BucketRelocationInfo {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("BucketRelocationInfo", 4)?;
+            state.serialize_field("offset", &self.offset)?;
+            state.serialize_field("size", &self.size)?;
+            state.serialize_field("version", &self.version)?;
+            state.serialize_field("entry_count", &self.entry_count)?;
+            state.end()
+        }
+    }
+
+    impl Serialize for PatchInfo {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("PatchInfo", 2)?;
+            state.serialize_field("relocation", &self.info)?;
+            state.serialize_field("subsection", &self.info_2)?;
+            state.end()
+        }
+    }
 
-                RomFs::new(romfs_reader)
+    impl Serialize for FileSystemHeader {
+        fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+            let mut state = serializer.serialize_struct("FileSystemHeader", 7)?;
+            state.serialize_field("version", &self.version)?;
+            state.serialize_field("fs_type", &self.fs_type)?;
+            state.serialize_field("hash_type", &self.hash_type)?;
+            state.serialize_field("encryption_type", &self.encryption_type)?;
+            match (
+                self.get_hierarchical_sha256(),
+                self.get_hierarchical_integrity(),
+            ) {
+                (Some(hash_info), _) => state.serialize_field("hash_info", hash_info)?,
+                (_, Some(hash_info)) => state.serialize_field("hash_info", hash_info)?,
+                (None, None) => state.serialize_field("hash_info", &())?,
             }
-            enc_type => todo!("Unsupported crypto type: {:?}", enc_type),
+            state.serialize_field("patch_info", &self.patch_info)?;
+            state.serialize_field("ctr", &self.ctr)?;
+            state.end()
         }
     }
 }
@@ -0,0 +1,382 @@
+use crate::error::{Error, Result};
+use crate::util::{aes128_ctr_crypt, LockShared, ReadSeek, Shared, SubReader};
+use std::io::{self, BufReader, Read, Seek, SeekFrom};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct NCZSection {
+    // Absolute offset and size within the NCA
+    pub offset: u64,
+    pub size: u64,
+    pub crypto_type: u64,
+    pub crypto_key: [u8; 0x10],
+    pub crypto_counter: [u8; 0x10],
+}
+
+impl NCZSection {
+    pub const SIZE: usize = 0x40;
+    pub const CRYPTO_TYPE_AES_CTR: u64 = 3;
+    pub const CRYPTO_TYPE_AES_CTR_EX: u64 = 4;
+
+    fn from_slice(data: &[u8]) -> Self {
+        Self {
+            offset: u64::from_le_bytes(data[0x0..0x8].try_into().unwrap()),
+            size: u64::from_le_bytes(data[0x8..0x10].try_into().unwrap()),
+            crypto_type: u64::from_le_bytes(data[0x10..0x18].try_into().unwrap()),
+            crypto_key: data[0x20..0x30].try_into().unwrap(),
+            crypto_counter: data[0x30..0x40].try_into().unwrap(),
+        }
+    }
+
+    #[inline]
+    fn is_encrypted(&self) -> bool {
+        matches!(
+            self.crypto_type,
+            Self::CRYPTO_TYPE_AES_CTR | Self::CRYPTO_TYPE_AES_CTR_EX
+        )
+    }
+}
+
+type SolidDecoder = zstd::stream::read::Decoder<'static, BufReader<SubReader>>;
+
+enum NCZData {
+    // Solid streams can't be seeked, so they are decoded forward from the last read position,
+    // starting over from the stream start on backward seeks
+    Solid {
+        // Range of the compressed stream within the NCZ
+        stream_range: (u64, u64),
+        // Decoder along with its position in the decompressed data
+        decoder: Option<(u64, SolidDecoder)>,
+    },
+    // Blocks are decompressed as needed, keeping the last one around
+    Blocks {
+        block_size: u64,
+        // Offset and size of each compressed block within the NCZ
+        block_ranges: Vec<(u64, u64)>,
+        cached_block: Option<(usize, Vec<u8>)>,
+    },
+}
+
+// Reads an NCZ as the original (encrypted) NCA: the first 0x4000 bytes are stored as-is, the rest is
+// zstd-compressed with the sections decrypted, so they are encrypted again with the stored keys.
+// NCA::new and friends can then be used on it like on any other NCA
+pub struct NCZReader {
+    inner: Shared<dyn ReadSeek>,
+    header_data: Vec<u8>,
+    sections: Vec<NCZSection>,
+    data: NCZData,
+    data_size: u64,
+    offset: u64,
+}
+
+impl NCZReader {
+    pub const UNCOMPRESSED_HEADER_SIZE: u64 = 0x4000;
+    pub const SECTION_MAGIC: [u8; 8] = *b"NCZSECTN";
+    pub const BLOCK_MAGIC: [u8; 8] = *b"NCZBLOCK";
+
+    pub fn new(inner: Shared<dyn ReadSeek>) -> Result<Self> {
+        let mut header_data = vec![0u8; Self::UNCOMPRESSED_HEADER_SIZE as usize];
+        let mut section_header_data = [0u8; 0x10];
+        let mut block_magic = [0u8; 8];
+        let mut sections: Vec<NCZSection> = Vec::new();
+        let data_offset = {
+            let mut reader = inner.lock_shared();
+            reader.seek(SeekFrom::Start(0))?;
+            reader.read_exact(&mut header_data)?;
+
+            reader.read_exact(&mut section_header_data)?;
+            if section_header_data[..0x8] != Self::SECTION_MAGIC {
+                return Err(Error::InvalidData(String::from(
+                    "Invalid NCZ section header magic",
+                )));
+            }
+            let section_count = u64::from_le_bytes(section_header_data[0x8..].try_into().unwrap());
+            let mut section_data = [0u8; NCZSection::SIZE];
+            for _ in 0..section_count {
+                reader.read_exact(&mut section_data)?;
+                let section = NCZSection::from_slice(&section_data);
+                if section.offset.checked_add(section.size).is_none() {
+                    return Err(Error::InvalidData(String::from(
+                        "Invalid NCZ section range",
+                    )));
+                }
+                sections.push(section);
+            }
+
+            let data_offset = reader.stream_position()?;
+            let read_size = reader.read(&mut block_magic)?;
+            if read_size < block_magic.len() {
+                block_magic = [0; 8];
+            }
+            reader.seek(SeekFrom::Start(data_offset))?;
+            data_offset
+        };
+
+        let (data, data_size) = match block_magic == Self::BLOCK_MAGIC {
+            true => Self::read_block_header(&inner, data_offset)?,
+            false => {
+                let stream_size = inner.lock_shared().seek(SeekFrom::End(0))?;
+                let stream_range = (data_offset, stream_size - data_offset);
+                let data_size = Self::get_solid_size(&inner, stream_range)?;
+                (
+                    NCZData::Solid {
+                        stream_range,
+                        decoder: None,
+                    },
+                    data_size,
+                )
+            }
+        };
+
+        Ok(Self {
+            inner,
+            header_data,
+            sections,
+            data,
+            data_size,
+            offset: 0,
+        })
+    }
+
+    // Taken from the frame header when it's there, otherwise the stream has to be decoded once (without
+    // keeping anything) to know it
+    fn get_solid_size(inner: &Shared<dyn ReadSeek>, stream_range: (u64, u64)) -> Result<u64> {
+        let mut frame_header = Vec::new();
+        SubReader::new(inner.clone(), stream_range.0, stream_range.1)
+            // Maximum size of a zstd frame header
+            .take(18)
+            .read_to_end(&mut frame_header)?;
+        if let Ok(Some(data_size)) = zstd::zstd_safe::get_frame_content_size(&frame_header) {
+            return Ok(data_size);
+        }
+
+        let mut decoder = Self::new_solid_decoder(inner, stream_range)?;
+        Ok(io::copy(&mut decoder, &mut io::sink())?)
+    }
+
+    fn new_solid_decoder(
+        inner: &Shared<dyn ReadSeek>,
+        stream_range: (u64, u64),
+    ) -> io::Result<SolidDecoder> {
+        zstd::stream::read::Decoder::new(SubReader::new(
+            inner.clone(),
+            stream_range.0,
+            stream_range.1,
+        ))
+    }
+
+    fn read_block_header(inner: &Shared<dyn ReadSeek>, offset: u64) -> Result<(NCZData, u64)> {
+        let mut reader = inner.lock_shared();
+        reader.seek(SeekFrom::Start(offset))?;
+
+        // Magic, version, type, unused, block size exponent, block count, decompressed size
+        let mut block_header_data = [0u8; 0x18];
+        reader.read_exact(&mut block_header_data)?;
+        let block_size_exponent = block_header_data[0xB] as u32;
+        let block_count = u32::from_le_bytes(block_header_data[0xC..0x10].try_into().unwrap());
+        let data_size = u64::from_le_bytes(block_header_data[0x10..0x18].try_into().unwrap());
+        let block_size = 1u64
+            .checked_shl(block_size_exponent)
+            .filter(|block_size| *block_size > 1)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid NCZ block size")))?;
+        if data_size.div_ceil(block_size) != block_count as u64 {
+            return Err(Error::InvalidData(String::from(
+                "NCZ block count doesn't match the decompressed size",
+            )));
+        }
+
+        // The block size table and the blocks themselves have to fit in the stream, so that bogus
+        // counts and sizes can't lead to huge allocations
+        let block_sizes_offset = reader.stream_position()?;
+        let stream_size = reader.seek(SeekFrom::End(0))?;
+        reader.seek(SeekFrom::Start(block_sizes_offset))?;
+        let block_sizes_size = block_count as u64 * 4;
+        if block_sizes_size > stream_size.saturating_sub(block_sizes_offset) {
+            return Err(Error::UnexpectedEof(String::from(
+                "NCZ block size table exceeds the stream",
+            )));
+        }
+
+        let mut block_sizes_data = vec![0u8; block_sizes_size as usize];
+        reader.read_exact(&mut block_sizes_data)?;
+        let mut block_offset = block_sizes_offset + block_sizes_size;
+        let mut block_ranges: Vec<(u64, u64)> = Vec::with_capacity(block_count as usize);
+        for block_size_data in block_sizes_data.chunks(4) {
+            let compressed_size = u32::from_le_bytes(block_size_data.try_into().unwrap()) as u64;
+            block_ranges.push((block_offset, compressed_size));
+            block_offset = block_offset
+                .checked_add(compressed_size)
+                .filter(|block_end| *block_end <= stream_size)
+                .ok_or_else(|| {
+                    Error::UnexpectedEof(String::from("NCZ blocks exceed the stream"))
+                })?;
+        }
+
+        Ok((
+            NCZData::Blocks {
+                block_size,
+                block_ranges,
+                cached_block: None,
+            },
+            data_size,
+        ))
+    }
+
+    #[inline]
+    pub fn get_sections(&self) -> &[NCZSection] {
+        &self.sections
+    }
+
+    // Size of the original NCA
+    #[inline]
+    pub fn get_nca_size(&self) -> u64 {
+        Self::UNCOMPRESSED_HEADER_SIZE + self.data_size
+    }
+
+    // Reads decompressed (plaintext) data at an offset relative to the end of the stored header
+    fn read_data(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        if offset >= self.data_size {
+            return Ok(0);
+        }
+
+        let data_size = self.data_size;
+        match &mut self.data {
+            NCZData::Solid {
+                stream_range,
+                decoder,
+            } => {
+                let (decoded_offset, mut solid_decoder) = match decoder.take() {
+                    Some((decoded_offset, solid_decoder)) if decoded_offset <= offset => {
+                        (decoded_offset, solid_decoder)
+                    }
+                    _ => (0, Self::new_solid_decoder(&self.inner, *stream_range)?),
+                };
+
+                // Errors leave the decoder at an unknown position, so it's only kept on success
+                let skip_size = offset - decoded_offset;
+                if io::copy(&mut (&mut solid_decoder).take(skip_size), &mut io::sink())?
+                    != skip_size
+                {
+                    return Err(io::Error::new(
+                        io::ErrorKind::UnexpectedEof,
+                        "Truncated NCZ data",
+                    ));
+                }
+                let read_size = std::cmp::min(buf.len() as u64, data_size - offset) as usize;
+                solid_decoder.read_exact(&mut buf[..read_size])?;
+                *decoder = Some((offset + read_size as u64, solid_decoder));
+                Ok(read_size)
+            }
+            NCZData::Blocks {
+                block_size,
+                block_ranges,
+                cached_block,
+            } => {
+                let block_idx = (offset / *block_size) as usize;
+                if cached_block.as_ref().map(|(idx, _)| *idx) != Some(block_idx) {
+                    let (block_offset, compressed_size) = block_ranges[block_idx];
+                    let block_start = block_idx as u64 * *block_size;
+                    let decompressed_size = std::cmp::min(*block_size, data_size - block_start);
+
+                    let mut compressed_block = vec![0u8; compressed_size as usize];
+                    {
+                        let mut inner = self.inner.lock_shared();
+                        inner.seek(SeekFrom::Start(block_offset))?;
+                        inner.read_exact(&mut compressed_block)?;
+                    }
+
+                    // Blocks which wouldn't get any smaller are stored uncompressed. The others are
+                    // decompressed as a stream, since the block size alone may be huge
+                    let block = match compressed_size < decompressed_size {
+                        true => {
+                            let mut block = Vec::new();
+                            zstd::stream::Decoder::with_buffer(&compressed_block[..])?
+                                .take(decompressed_size + 1)
+                                .read_to_end(&mut block)?;
+                            block
+                        }
+                        false => compressed_block,
+                    };
+                    if block.len() as u64 != decompressed_size {
+                        return Err(io::Error::new(
+                            io::ErrorKind::InvalidData,
+                            format!("Invalid size for NCZ block {}", block_idx),
+                        ));
+                    }
+                    *cached_block = Some((block_idx, block));
+                }
+
+                let block = &cached_block.as_ref().unwrap().1;
+                let start = (offset % *block_size) as usize;
+                let end = std::cmp::min(block.len(), start + buf.len());
+                buf[..end - start].copy_from_slice(&block[start..end]);
+                Ok(end - start)
+            }
+        }
+    }
+
+    // Encrypts back the parts of the section data in `buf` (read at the absolute `offset`). Section
+    // ranges were checked when opening, and `buf` is within the NCA
+    fn encrypt_sections(&self, offset: u64, buf: &mut [u8]) {
+        let end_offset = offset + buf.len() as u64;
+        for section in self
+            .sections
+            .iter()
+            .filter(|section| section.is_encrypted())
+        {
+            let start = std::cmp::max(offset, section.offset);
+            let end = std::cmp::min(end_offset, section.offset + section.size);
+            if start >= end {
+                continue;
+            }
+
+            let ctr = u64::from_be_bytes(section.crypto_counter[..0x8].try_into().unwrap());
+            aes128_ctr_crypt(
+                &mut buf[(start - offset) as usize..(end - offset) as usize],
+                start,
+                ctr,
+                &section.crypto_key,
+            );
+        }
+    }
+}
+
+impl Read for NCZReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let offset = self.offset;
+        let read_size = if offset < Self::UNCOMPRESSED_HEADER_SIZE {
+            let start = offset as usize;
+            let end = std::cmp::min(self.header_data.len(), start + buf.len());
+            buf[..end - start].copy_from_slice(&self.header_data[start..end]);
+            end - start
+        } else {
+            let read_size =
+                self.read_data(offset - Self::UNCOMPRESSED_HEADER_SIZE, &mut buf[..])?;
+            self.encrypt_sections(offset, &mut buf[..read_size]);
+            read_size
+        };
+
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for NCZReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.offset.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.get_nca_size().checked_add_signed(offset),
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(new_offset)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of NCA",
+            )),
+        }
+    }
+}
@@ -0,0 +1,136 @@
+use crate::error::{Error, Result};
+use crate::util::ReadSeek;
+use std::io::SeekFrom;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ACID {
+    pub version: u8,
+    pub flags: u32,
+    pub program_id_min: u64,
+    pub program_id_max: u64,
+    // Public key for the second header signature of the NCA this comes from
+    pub nca_signature_modulus: [u8; 0x100],
+}
+
+impl ACID {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"ACID");
+    // Signature and public key come first
+    const MAGIC_OFFSET: usize = 0x200;
+    pub const HEADER_SIZE: usize = 0x240;
+
+    fn from_slice(data: &[u8]) -> Result<Self> {
+        let magic = u32::from_le_bytes(
+            data[Self::MAGIC_OFFSET..Self::MAGIC_OFFSET + 4]
+                .try_into()
+                .unwrap(),
+        );
+        if magic != Self::MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: Self::MAGIC,
+                found: magic,
+            });
+        }
+
+        Ok(Self {
+            version: data[0x208],
+            flags: u32::from_le_bytes(data[0x20C..0x210].try_into().unwrap()),
+            program_id_min: u64::from_le_bytes(data[0x210..0x218].try_into().unwrap()),
+            program_id_max: u64::from_le_bytes(data[0x218..0x220].try_into().unwrap()),
+            nca_signature_modulus: data[0x100..0x200].try_into().unwrap(),
+        })
+    }
+
+    #[inline]
+    pub fn is_production(&self) -> bool {
+        (self.flags & 0x1) != 0
+    }
+
+    #[inline]
+    pub fn is_unqualified_approval(&self) -> bool {
+        (self.flags & 0x2) != 0
+    }
+
+    // Application, applet, secure system or non-secure system
+    #[inline]
+    pub fn get_memory_region(&self) -> u8 {
+        ((self.flags >> 2) & 0xF) as u8
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NPDM {
+    pub flags: u8,
+    pub main_thread_priority: u8,
+    pub main_thread_core_number: u8,
+    pub system_resource_size: u32,
+    pub version: u32,
+    pub main_thread_stack_size: u32,
+    pub name: String,
+    pub product_code: String,
+    pub acid: ACID,
+}
+
+fn read_str(data: &[u8]) -> Result<String> {
+    let str_len = data.iter().position(|c| *c == 0).unwrap_or(data.len());
+    String::from_utf8(data[..str_len].to_vec())
+        .map_err(|_| Error::InvalidData(String::from("Invalid NPDM string")))
+}
+
+impl NPDM {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"META");
+    pub const HEADER_SIZE: usize = 0x80;
+
+    // Only the META header and the ACID header are parsed, not the ACI or the access control data
+    pub fn from<R: ReadSeek>(mut reader: R) -> Result<Self> {
+        reader.seek(SeekFrom::Start(0))?;
+        let mut data = [0u8; Self::HEADER_SIZE];
+        reader.read_exact(&mut data)?;
+
+        let magic = u32::from_le_bytes(data[0x0..0x4].try_into().unwrap());
+        if magic != Self::MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: Self::MAGIC,
+                found: magic,
+            });
+        }
+
+        let acid_offset = u32::from_le_bytes(data[0x78..0x7C].try_into().unwrap());
+        let acid_size = u32::from_le_bytes(data[0x7C..0x80].try_into().unwrap());
+        if (acid_size as usize) < ACID::HEADER_SIZE {
+            return Err(Error::InvalidData(format!(
+                "NPDM ACID too small (0x{:X} bytes)",
+                acid_size
+            )));
+        }
+        let mut acid_data = [0u8; ACID::HEADER_SIZE];
+        reader.seek(SeekFrom::Start(acid_offset as u64))?;
+        reader.read_exact(&mut acid_data)?;
+
+        Ok(Self {
+            flags: data[0xC],
+            main_thread_priority: data[0xE],
+            main_thread_core_number: data[0xF],
+            system_resource_size: u32::from_le_bytes(data[0x14..0x18].try_into().unwrap()),
+            version: u32::from_le_bytes(data[0x18..0x1C].try_into().unwrap()),
+            main_thread_stack_size: u32::from_le_bytes(data[0x1C..0x20].try_into().unwrap()),
+            name: read_str(&data[0x20..0x30])?,
+            product_code: read_str(&data[0x30..0x40])?,
+            acid: ACID::from_slice(&acid_data)?,
+        })
+    }
+
+    #[inline]
+    pub fn get_name(&self) -> &str {
+        &self.name
+    }
+
+    #[inline]
+    pub fn is_64_bit(&self) -> bool {
+        (self.flags & 0x1) != 0
+    }
+
+    #[inline]
+    pub fn get_address_space_type(&self) -> u8 {
+        (self.flags >> 1) & 0x7
+    }
+}
@@ -0,0 +1,108 @@
+use crate::error::{Error, Result};
+use crate::key::{Keyset, Ticket};
+use crate::nca::NCA;
+use crate::pfs0::PFS0;
+use crate::util::{new_shared, ReadSeek, Shared};
+use std::fs::File;
+use std::path::Path;
+
+pub struct NSP {
+    pfs0: PFS0,
+    nca_files: Vec<(usize, String)>,
+    tickets: Vec<Ticket>,
+    // Rights IDs of the personalized tickets, which can't be used
+    personalized_rights_ids: Vec<[u8; 0x10]>,
+}
+
+impl NSP {
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        let mut pfs0 = PFS0::new(reader)?;
+
+        let mut nca_files: Vec<(usize, String)> = Vec::new();
+        let mut tickets: Vec<Ticket> = Vec::new();
+        let mut personalized_rights_ids: Vec<[u8; 0x10]> = Vec::new();
+        for (idx, file_name) in pfs0.list_files()?.into_iter().enumerate() {
+            if file_name.ends_with(".nca") {
+                nca_files.push((idx, file_name));
+            } else if file_name.ends_with(".tik") {
+                match Ticket::from(pfs0.get_file_reader(idx)?) {
+                    Ok(ticket) => tickets.push(ticket),
+                    // Only an error for the NCAs which actually need it
+                    Err(Error::PersonalizedTicket) => {
+                        personalized_rights_ids
+                            .push(Ticket::read_rights_id(pfs0.get_file_reader(idx)?)?);
+                    }
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        Ok(Self {
+            pfs0,
+            nca_files,
+            tickets,
+            personalized_rights_ids,
+        })
+    }
+
+    #[inline]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(new_shared(File::open(path)?))
+    }
+
+    pub fn list_ncas(&self) -> Vec<String> {
+        self.nca_files
+            .iter()
+            .map(|(_, file_name)| file_name.clone())
+            .collect()
+    }
+
+    #[inline]
+    pub fn get_tickets(&self) -> &[Ticket] {
+        &self.tickets
+    }
+
+    fn open_nca_file(&mut self, pfs0_idx: usize, keyset: &Keyset) -> Result<NCA> {
+        // The header needs to be decrypted first to know which ticket (if any) to use
+        let header_reader: Shared<dyn ReadSeek> = new_shared(self.pfs0.get_file_reader(pfs0_idx)?);
+        let (header, _, _, _) = NCA::read_headers(&header_reader, keyset)?;
+
+        let title_key = match header.has_rights_id() {
+            true => self
+                .tickets
+                .iter()
+                .find(|ticket| ticket.matches_rights_id(&header.rights_id))
+                .map(|ticket| ticket.get_title_key()),
+            false => None,
+        };
+        if header.has_rights_id()
+            && title_key.is_none()
+            && self.personalized_rights_ids.contains(&header.rights_id)
+        {
+            return Err(Error::PersonalizedTicket);
+        }
+
+        let nca_reader = new_shared(self.pfs0.get_file_reader(pfs0_idx)?);
+        NCA::new(nca_reader, keyset, title_key)
+    }
+
+    pub fn open_nca(&mut self, idx: usize, keyset: &Keyset) -> Result<NCA> {
+        if idx >= self.nca_files.len() {
+            return Err(Error::InvalidIndex(idx));
+        }
+
+        let pfs0_idx = self.nca_files[idx].0;
+        self.open_nca_file(pfs0_idx, keyset)
+    }
+
+    pub fn open_cnmt(&mut self, keyset: &Keyset) -> Result<NCA> {
+        let pfs0_idx = self
+            .nca_files
+            .iter()
+            .find(|(_, file_name)| file_name.ends_with(".cnmt.nca"))
+            .map(|(idx, _)| *idx)
+            .ok_or_else(|| Error::NotFound(String::from("CNMT NCA")))?;
+
+        self.open_nca_file(pfs0_idx, keyset)
+    }
+}
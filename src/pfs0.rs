@@ -1,5 +1,13 @@
-use crate::util::{reader_read_val, ReadSeek, Shared};
-use std::io::{Error, ErrorKind, Read, Result, Seek, SeekFrom};
+use crate::error::{Error, Result};
+use crate::util::{
+    align_up, check_path_item, get_string_table_name, new_shared, new_shared_bytes, read_to_vec,
+    reader_read_string_table, reader_read_val, sha256_reader, LockShared, ProgressEvent,
+    ProgressTracker, ReadSeek, Shared, SubReader,
+};
+use std::collections::HashMap;
+use std::fs::{create_dir_all, File};
+use std::io::{copy, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
@@ -23,116 +31,191 @@ pub struct FileEntry {
     pub reserved: [u8; 0x4],
 }
 
-pub struct PFS0FileReader {
-    inner: Shared<dyn ReadSeek>,
-    read_offset: u64,
-    file_size: u64,
-}
-
-unsafe impl Send for PFS0FileReader {}
-unsafe impl Sync for PFS0FileReader {}
-
-impl Read for PFS0FileReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        self.inner
-            .lock()
-            .unwrap()
-            .seek(SeekFrom::Start(self.read_offset))?;
-        self.inner.lock().unwrap().read(buf)
-    }
-}
-
-impl Seek for PFS0FileReader {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        match pos {
-            SeekFrom::Start(offset) => {
-                let new_offset = self.read_offset + offset as u64;
-                if new_offset > self.file_size {
-                    return Err(Error::new(
-                        ErrorKind::InvalidInput,
-                        "Attempted to seek past end of file",
-                    ));
-                }
-                self.inner
-                    .lock()
-                    .unwrap()
-                    .seek(SeekFrom::Start(self.read_offset + offset))
-            }
-            SeekFrom::Current(offset) => {
-                let new_offset =
-                    (self.inner.lock().unwrap().stream_position()? as i64 + offset) as u64;
-                if new_offset > self.read_offset + self.file_size || new_offset < self.read_offset {
-                    return Err(Error::new(ErrorKind::InvalidInput, "Seek out of bounds"));
-                }
-                self.inner.lock().unwrap().seek(SeekFrom::Start(new_offset))
-            }
-            SeekFrom::End(offset) => {
-                let new_offset = (self.read_offset as i64 + self.file_size as i64 + offset) as u64;
-                if new_offset > self.read_offset + self.file_size || new_offset < self.read_offset {
-                    return Err(Error::new(ErrorKind::InvalidInput, "Seek out of bounds"));
-                }
-                self.inner.lock().unwrap().seek(SeekFrom::End(offset))
-            }
-        }
-    }
-}
+// Files are plain ranges of the PFS0 data
+pub type PFS0FileReader = SubReader;
 
 pub struct PFS0 {
     reader: Shared<dyn ReadSeek>,
     header: Header,
     file_entries: Vec<FileEntry>,
-    string_table: Vec<u8>,
+    file_names: Vec<String>,
+    file_idxs: HashMap<String, usize>,
 }
 
 impl PFS0 {
     pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
         let header: Header = reader_read_val(&reader)?;
         if header.magic != Header::MAGIC {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid PFS0 magic"));
+            return Err(Error::InvalidMagic {
+                expected: Header::MAGIC,
+                found: header.magic,
+            });
         }
 
-        let mut file_entries: Vec<FileEntry> = Vec::with_capacity(header.file_count as usize);
+        // Not preallocated, the count may be bogus
+        let mut file_entries: Vec<FileEntry> = Vec::new();
 
         for _ in 0..header.file_count {
             let file_entry: FileEntry = reader_read_val(&reader)?;
             file_entries.push(file_entry);
         }
 
-        let mut str_table = vec![0u8; header.string_table_size as usize];
-        reader.lock().unwrap().read_exact(&mut str_table)?;
+        let str_table = reader_read_string_table(&reader, header.string_table_size)?;
+
+        let file_names = file_entries
+            .iter()
+            .map(|entry| get_string_table_name(&str_table, entry.string_table_offset))
+            .collect::<Result<Vec<String>>>()?;
+
+        // Offsets and sizes are used as-is from here on, so file ranges (and their total size) must fit
+        let data_offset = (std::mem::size_of::<Header>()
+            + std::mem::size_of::<FileEntry>() * file_entries.len()
+            + str_table.len()) as u64;
+        file_entries.iter().try_fold(0usize, |total_size, entry| {
+            data_offset
+                .checked_add(entry.offset)
+                .and_then(|file_offset| file_offset.checked_add(entry.size as u64))
+                .and(total_size.checked_add(entry.size))
+                .ok_or_else(|| {
+                    Error::InvalidData(format!(
+                        "Invalid PFS0 file range (offset 0x{:X}, size 0x{:X})",
+                        entry.offset, entry.size
+                    ))
+                })
+        })?;
+
+        // Names are looked up way more often than listed
+        let mut file_idxs: HashMap<String, usize> = HashMap::with_capacity(file_names.len());
+        for (idx, file_name) in file_names.iter().enumerate() {
+            file_idxs.entry(file_name.clone()).or_insert(idx);
+        }
 
         Ok(Self {
             reader,
             header,
             file_entries,
-            string_table: str_table,
+            file_names,
+            file_idxs,
+        })
+    }
+
+    #[inline]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(new_shared(File::open(path)?))
+    }
+
+    // For untrusted in-memory data (fuzzing, for one): errors out on malformed data, never panics
+    #[inline]
+    pub fn try_new(data: &[u8]) -> Result<Self> {
+        Self::new(new_shared_bytes(data.to_vec()))
+    }
+
+    // Only the metadata (header, entries and string table) is read asynchronously and parsed as usual,
+    // file data being read through the returned AsyncPFS0
+    #[cfg(feature = "tokio")]
+    pub async fn new_async<R: crate::util::AsyncReadSeek>(mut reader: R) -> Result<AsyncPFS0<R>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let mut metadata = vec![0u8; std::mem::size_of::<Header>()];
+        reader.seek(SeekFrom::Start(0)).await?;
+        reader.read_exact(&mut metadata).await?;
+
+        let file_count = u32::from_le_bytes(metadata[0x4..0x8].try_into().unwrap()) as usize;
+        let string_table_size = u32::from_le_bytes(metadata[0x8..0xC].try_into().unwrap()) as usize;
+        let metadata_size = file_count
+            .checked_mul(std::mem::size_of::<FileEntry>())
+            .and_then(|entries_size| entries_size.checked_add(string_table_size))
+            .and_then(|size| size.checked_add(metadata.len()))
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid PFS0 header")))?;
+        // Read as it comes like on the sync path, parsing reports truncated metadata
+        let header_size = metadata.len();
+        (&mut reader)
+            .take((metadata_size - header_size) as u64)
+            .read_to_end(&mut metadata)
+            .await?;
+
+        Ok(AsyncPFS0 {
+            reader,
+            pfs0: Self::new(new_shared_bytes(metadata))?,
         })
     }
 
+    #[inline]
     pub fn list_files(&self) -> Result<Vec<String>> {
-        let mut file_names: Vec<String> = Vec::with_capacity(self.file_entries.len());
+        Ok(self.file_names.clone())
+    }
 
-        for entry in self.file_entries.iter() {
-            let mut bytes: Vec<u8> = Vec::new();
+    // Names are decoded once on creation, in entry order
+    #[inline]
+    pub fn get_file_names(&self) -> &[String] {
+        &self.file_names
+    }
 
-            let str_t = &self.string_table[entry.string_table_offset as usize..];
-            for c in str_t {
-                if *c == 0 {
-                    break;
-                }
+    #[inline]
+    pub fn get_file_count(&self) -> usize {
+        self.file_entries.len()
+    }
 
-                bytes.push(*c);
-            }
+    // Sum of all the file sizes
+    #[inline]
+    pub fn get_total_size(&self) -> usize {
+        self.file_entries.iter().map(|entry| entry.size).sum()
+    }
 
-            file_names.push(String::from_utf8(bytes).unwrap());
-        }
+    #[inline]
+    pub fn entries(&self) -> PFS0Entries<'_> {
+        PFS0Entries { pfs0: self, idx: 0 }
+    }
+
+    // Absolute offset and size of the file data
+    fn get_file_range(&self, idx: usize) -> Result<(u64, usize)> {
+        let entry = self.file_entries.get(idx).ok_or(Error::InvalidIndex(idx))?;
+        Ok((self.get_data_offset() + entry.offset, entry.size))
+    }
 
-        Ok(file_names)
+    // File data starts right after the string table
+    fn get_data_offset(&self) -> u64 {
+        (std::mem::size_of::<Header>()
+            + std::mem::size_of::<FileEntry>() * self.header.file_count as usize
+            + self.header.string_table_size as usize) as u64
+    }
+
+    #[inline]
+    pub fn find_file(&self, name: &str) -> Option<usize> {
+        self.file_idxs.get(name).copied()
+    }
+
+    fn find_file_by_name(&self, name: &str) -> Result<usize> {
+        self.find_file(name)
+            .ok_or_else(|| Error::NotFound(format!("PFS0 file '{}'", name)))
+    }
+
+    #[inline]
+    pub fn get_file_size_by_name(&mut self, name: &str) -> Result<usize> {
+        let idx = self.find_file_by_name(name)?;
+        self.get_file_size(idx)
+    }
+
+    #[inline]
+    pub fn read_file_by_name(
+        &mut self,
+        name: &str,
+        offset: usize,
+        buf: &mut [u8],
+    ) -> Result<usize> {
+        let idx = self.find_file_by_name(name)?;
+        self.read_file(idx, offset, buf)
+    }
+
+    #[inline]
+    pub fn get_file_reader_by_name(&mut self, name: &str) -> Result<PFS0FileReader> {
+        let idx = self.find_file_by_name(name)?;
+        self.get_file_reader(idx)
     }
 
     pub fn get_file_size(&mut self, idx: usize) -> Result<usize> {
         if idx >= self.file_entries.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+            return Err(Error::InvalidIndex(idx));
         }
 
         Ok(self.file_entries[idx].size)
@@ -140,45 +223,347 @@ impl PFS0 {
 
     pub fn read_file(&mut self, idx: usize, offset: usize, buf: &mut [u8]) -> Result<usize> {
         if idx >= self.file_entries.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+            return Err(Error::InvalidIndex(idx));
         }
 
         let entry = &self.file_entries[idx];
-        if (offset + buf.len()) > entry.size {
-            return Err(Error::new(ErrorKind::UnexpectedEof, "EOF reached"));
+        let read_end = offset
+            .checked_add(buf.len())
+            .ok_or_else(|| Error::InvalidInput(format!("Invalid read offset 0x{:X}", offset)))?;
+        if read_end > entry.size {
+            return Err(Error::UnexpectedEof(String::from("EOF reached")));
+        }
+        // Zero-size files may be right at the end of the PFS0, nothing to seek to
+        if buf.is_empty() {
+            return Ok(0);
         }
 
-        let base_offset = std::mem::size_of::<Header>()
-            + std::mem::size_of::<FileEntry>() * self.header.file_count as usize
-            + self.header.string_table_size as usize;
-        let base_read_offset = base_offset + entry.offset as usize;
-        let read_offset = base_read_offset + offset;
+        let read_offset = self
+            .get_data_offset()
+            .checked_add(entry.offset)
+            .and_then(|file_offset| file_offset.checked_add(offset as u64))
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid PFS0 file offset")))?;
 
         self.reader
-            .lock()
-            .unwrap()
-            .seek(SeekFrom::Start(read_offset as u64))?;
-        self.reader.lock().unwrap().read(buf)
+            .lock_shared()
+            .seek(SeekFrom::Start(read_offset))?;
+        Ok(self.reader.lock_shared().read(buf)?)
     }
 
     pub fn get_file_reader(&mut self, idx: usize) -> Result<PFS0FileReader> {
-        if idx >= self.file_entries.len() {
-            return Err(Error::new(ErrorKind::InvalidInput, "Invalid file index"));
+        let (read_offset, file_size) = self.get_file_range(idx)?;
+        let mut reader = PFS0FileReader::new(self.reader.clone(), read_offset, file_size as u64);
+        reader.seek(SeekFrom::Start(0))?;
+        Ok(reader)
+    }
+
+    // For layered containers, the file must be a PFS0 itself (InvalidMagic otherwise)
+    pub fn open_sub_pfs0(&mut self, idx: usize) -> Result<PFS0> {
+        let file_reader = self.get_file_reader(idx)?;
+        PFS0::new(new_shared(file_reader))
+    }
+
+    pub fn read_file_to_vec(&mut self, idx: usize) -> Result<Vec<u8>> {
+        let mut file_reader = self.get_file_reader(idx)?;
+        let file_size = file_reader.get_size();
+        read_to_vec(&mut file_reader, file_size)
+    }
+
+    // SHA-256 of the file data, which is streamed instead of loaded
+    pub fn hash_file(&mut self, idx: usize) -> Result<[u8; 0x20]> {
+        let mut file_reader = self.get_file_reader(idx)?;
+        let file_size = file_reader.get_size();
+        sha256_reader(&mut file_reader, file_size)
+    }
+
+    #[inline]
+    pub fn extract_to(&mut self, out_dir: &Path) -> Result<()> {
+        self.extract_to_with_progress(out_dir, &mut |_| {})
+    }
+
+    pub fn extract_to_with_progress(
+        &mut self,
+        out_dir: &Path,
+        progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<()> {
+        create_dir_all(out_dir)?;
+
+        let total_size = self
+            .file_entries
+            .iter()
+            .map(|entry| entry.size as u64)
+            .sum();
+        let mut progress_tracker = ProgressTracker::new(progress, total_size);
+        for (idx, file_name) in self.file_names.iter().enumerate() {
+            let mut out_file = File::create(out_dir.join(check_path_item(file_name)?))?;
+            let entry = &self.file_entries[idx];
+            let mut file_reader = PFS0FileReader::new(
+                self.reader.clone(),
+                self.get_data_offset() + entry.offset,
+                entry.size as u64,
+            );
+            progress_tracker.copy(
+                file_name,
+                &mut file_reader,
+                &mut out_file,
+                entry.size as u64,
+            )?;
         }
 
-        let entry = &self.file_entries[idx];
-        let base_offset = std::mem::size_of::<Header>()
-            + std::mem::size_of::<FileEntry>() * self.header.file_count as usize
-            + self.header.string_table_size as usize;
-        let base_read_offset = base_offset + entry.offset as usize;
-        let read_offset = base_read_offset + entry.size as usize;
-        let reader = self.reader.clone();
-        let mut reader = PFS0FileReader {
-            inner: reader,
-            read_offset: read_offset as u64,
-            file_size: entry.size as u64,
+        Ok(())
+    }
+
+    // open_reader must open the same data this PFS0 was created from (once per worker), for files
+    // that means reopening the path, since File::try_clone handles share the same cursor
+    #[cfg(feature = "rayon")]
+    pub fn extract_to_parallel<F>(&self, out_dir: &Path, open_reader: F) -> Result<()>
+    where
+        F: Fn() -> Result<Shared<dyn ReadSeek>> + Sync,
+    {
+        create_dir_all(out_dir)?;
+
+        let mut files = Vec::with_capacity(self.get_file_count());
+        for entry in self.entries() {
+            let out_path = out_dir.join(check_path_item(&entry.name)?);
+            files.push((entry.offset, entry.size as u64, out_path));
+        }
+
+        crate::util::extract_files_parallel(&files, open_reader)
+    }
+}
+
+// PFS0 over an async reader, see PFS0::new_async
+#[cfg(feature = "tokio")]
+pub struct AsyncPFS0<R: crate::util::AsyncReadSeek> {
+    reader: R,
+    // Only backed by the metadata, used for lookups
+    pfs0: PFS0,
+}
+
+#[cfg(feature = "tokio")]
+impl<R: crate::util::AsyncReadSeek> AsyncPFS0<R> {
+    #[inline]
+    pub fn list_files(&self) -> Result<Vec<String>> {
+        self.pfs0.list_files()
+    }
+
+    #[inline]
+    pub fn get_file_names(&self) -> &[String] {
+        self.pfs0.get_file_names()
+    }
+
+    #[inline]
+    pub fn get_file_count(&self) -> usize {
+        self.pfs0.get_file_count()
+    }
+
+    #[inline]
+    pub fn entries(&self) -> PFS0Entries<'_> {
+        self.pfs0.entries()
+    }
+
+    #[inline]
+    pub fn find_file(&self, name: &str) -> Option<usize> {
+        self.pfs0.find_file(name)
+    }
+
+    #[inline]
+    pub fn get_file_size(&self, idx: usize) -> Result<usize> {
+        Ok(self.pfs0.get_file_range(idx)?.1)
+    }
+
+    pub async fn read_file(&mut self, idx: usize, offset: usize, buf: &mut [u8]) -> Result<usize> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let (file_offset, file_size) = self.pfs0.get_file_range(idx)?;
+        let read_end = offset
+            .checked_add(buf.len())
+            .ok_or_else(|| Error::InvalidInput(format!("Invalid read offset 0x{:X}", offset)))?;
+        if read_end > file_size {
+            return Err(Error::UnexpectedEof(String::from("EOF reached")));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let read_offset = file_offset
+            .checked_add(offset as u64)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid PFS0 file offset")))?;
+        self.reader.seek(SeekFrom::Start(read_offset)).await?;
+        self.reader.read_exact(buf).await?;
+        Ok(buf.len())
+    }
+
+    // The size comes from the header, so the data is read as it comes like read_to_vec does
+    pub async fn read_file_to_vec(&mut self, idx: usize) -> Result<Vec<u8>> {
+        use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+        let (file_offset, file_size) = self.pfs0.get_file_range(idx)?;
+        self.reader.seek(SeekFrom::Start(file_offset)).await?;
+        let mut file_data: Vec<u8> = Vec::new();
+        (&mut self.reader)
+            .take(file_size as u64)
+            .read_to_end(&mut file_data)
+            .await?;
+        if file_data.len() != file_size {
+            return Err(Error::UnexpectedEof(format!(
+                "Expected 0x{:X} bytes, found 0x{:X}",
+                file_size,
+                file_data.len()
+            )));
+        }
+
+        Ok(file_data)
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PFS0Entry {
+    pub name: String,
+    pub size: usize,
+    // Absolute offset of the file data within the PFS0
+    pub offset: u64,
+}
+
+pub struct PFS0Entries<'a> {
+    pfs0: &'a PFS0,
+    idx: usize,
+}
+
+impl Iterator for PFS0Entries<'_> {
+    type Item = PFS0Entry;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.pfs0.file_entries.get(self.idx)?;
+        let name = self.pfs0.file_names[self.idx].clone();
+        self.idx += 1;
+
+        Some(PFS0Entry {
+            name,
+            size: entry.size,
+            offset: self.pfs0.get_data_offset() + entry.offset,
+        })
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let left = self.pfs0.file_entries.len() - self.idx;
+        (left, Some(left))
+    }
+}
+
+impl ExactSizeIterator for PFS0Entries<'_> {}
+
+impl<'a> IntoIterator for &'a PFS0 {
+    type Item = PFS0Entry;
+    type IntoIter = PFS0Entries<'a>;
+
+    #[inline]
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries()
+    }
+}
+
+pub struct PFS0Builder<'a> {
+    files: Vec<(String, Box<dyn ReadSeek + 'a>, u64)>,
+}
+
+impl<'a> PFS0Builder<'a> {
+    pub const HEADER_ALIGNMENT: usize = 0x10;
+
+    pub fn new() -> Self {
+        Self { files: Vec::new() }
+    }
+
+    // Names end up NUL-terminated in the string table, and are how files get looked up
+    pub fn add_file(
+        &mut self,
+        name: &str,
+        reader: impl ReadSeek + 'a,
+        size: u64,
+    ) -> Result<&mut Self> {
+        if name.contains('\0') {
+            return Err(Error::InvalidData(format!(
+                "PFS0 file name contains a NUL: '{}'",
+                name.escape_debug()
+            )));
+        }
+        if self.files.iter().any(|(file_name, _, _)| file_name == name) {
+            return Err(Error::InvalidData(format!(
+                "Duplicate PFS0 file name: '{}'",
+                name
+            )));
+        }
+
+        self.files
+            .push((String::from(name), Box::new(reader), size));
+        Ok(self)
+    }
+
+    pub fn write_to(self, mut out: impl Write) -> Result<()> {
+        let mut string_table: Vec<u8> = Vec::new();
+        let mut file_entries: Vec<FileEntry> = Vec::with_capacity(self.files.len());
+        let mut data_offset = 0u64;
+        for (name, _, size) in self.files.iter() {
+            file_entries.push(FileEntry {
+                offset: data_offset,
+                size: *size as usize,
+                string_table_offset: string_table.len() as u32,
+                reserved: [0; 0x4],
+            });
+            string_table.extend_from_slice(name.as_bytes());
+            string_table.push(0);
+            data_offset += size;
+        }
+
+        // The string table is padded so that the file data region starts aligned
+        let base_size = std::mem::size_of::<Header>()
+            + std::mem::size_of::<FileEntry>() * file_entries.len()
+            + string_table.len();
+        string_table.resize(
+            string_table.len() + align_up(base_size, Self::HEADER_ALIGNMENT) - base_size,
+            0,
+        );
+
+        let header = Header {
+            magic: Header::MAGIC,
+            file_count: file_entries.len() as u32,
+            string_table_size: string_table.len() as u32,
+            reserved: [0; 0x4],
         };
-        reader.seek(SeekFrom::Start(read_offset as u64))?;
-        Ok(reader)
+        out.write_all(&header.magic.to_le_bytes())?;
+        out.write_all(&header.file_count.to_le_bytes())?;
+        out.write_all(&header.string_table_size.to_le_bytes())?;
+        out.write_all(&header.reserved)?;
+        for entry in file_entries.iter() {
+            out.write_all(&entry.offset.to_le_bytes())?;
+            out.write_all(&(entry.size as u64).to_le_bytes())?;
+            out.write_all(&entry.string_table_offset.to_le_bytes())?;
+            out.write_all(&entry.reserved)?;
+        }
+        out.write_all(&string_table)?;
+
+        for (name, mut reader, size) in self.files {
+            reader.seek(SeekFrom::Start(0))?;
+            let copied_size = copy(&mut reader.take(size), &mut out)?;
+            if copied_size != size {
+                return Err(Error::UnexpectedEof(format!(
+                    "File '{}' is smaller than its specified size",
+                    name
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for PFS0Builder<'_> {
+    fn default() -> Self {
+        Self::new()
     }
 }
@@ -1,6 +1,14 @@
-use std::io::{Error, ErrorKind, Result, SeekFrom};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
+use std::fs::{create_dir_all, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
 
-use crate::util::{reader_read_val, ReadSeek, Shared};
+use crate::error::{Error, Result};
+use crate::util::{
+    align_up, check_path_item, new_shared, new_shared_bytes, read_to_vec, reader_read_val,
+    sha256_reader, LockShared, ProgressEvent, ProgressTracker, ReadSeek, Shared, SubReader,
+};
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
 #[repr(C)]
@@ -39,23 +47,71 @@ pub struct FileInfo {
     name_len: u32,
 }
 
+// Entries (info and name) must be fully inside their table
+fn check_table_entry(
+    table_name: &str,
+    table_size: u64,
+    offset: u32,
+    entry_size: u64,
+) -> Result<()> {
+    match (offset as u64).checked_add(entry_size) {
+        Some(entry_end) if entry_end <= table_size => Ok(()),
+        _ => Err(Error::InvalidData(format!(
+            "RomFs {} entry at offset 0x{:X} is outside of the {} table (size 0x{:X})",
+            table_name, offset, table_name, table_size
+        ))),
+    }
+}
+
+// Malformed tables can chain entries back to already visited ones, which would loop forever
+fn check_chain_offset(visited: &mut HashSet<u32>, table_name: &str, offset: u32) -> Result<()> {
+    match visited.insert(offset) {
+        true => Ok(()),
+        false => Err(Error::InvalidData(format!(
+            "RomFs {} entry at offset 0x{:X} is chained more than once",
+            table_name, offset
+        ))),
+    }
+}
+
+fn read_entry_name(
+    reader: &Shared<dyn ReadSeek>,
+    table_name: &str,
+    offset: u32,
+    name_len: u32,
+) -> Result<String> {
+    let name_data = read_to_vec(&mut *reader.lock_shared(), name_len as u64)?;
+    String::from_utf8(name_data).map_err(|_| {
+        Error::InvalidData(format!(
+            "Invalid UTF-8 name for the RomFs {} entry at offset 0x{:X}",
+            table_name, offset
+        ))
+    })
+}
+
 fn read_dir_info(
     reader: &Shared<dyn ReadSeek>,
     dir_table_offset: u64,
+    dir_table_size: u64,
     offset: u32,
     read_str: bool,
 ) -> Result<(DirectoryInfo, String)> {
+    let info_size = std::mem::size_of::<DirectoryInfo>() as u64;
+    check_table_entry("directory", dir_table_size, offset, info_size)?;
     reader
-        .lock()
-        .unwrap()
+        .lock_shared()
         .seek(SeekFrom::Start(dir_table_offset + offset as u64))?;
     let dir_info: DirectoryInfo = reader_read_val(reader)?;
 
     let name = match read_str {
         true => {
-            let mut name_data = vec![0u8; dir_info.name_len as usize];
-            reader.lock().unwrap().read_exact(&mut name_data)?;
-            String::from_utf8(name_data).unwrap()
+            check_table_entry(
+                "directory",
+                dir_table_size,
+                offset,
+                info_size + dir_info.name_len as u64,
+            )?;
+            read_entry_name(reader, "directory", offset, dir_info.name_len)?
         }
         false => String::new(),
     };
@@ -65,30 +121,45 @@ fn read_dir_info(
 fn read_file_info(
     reader: &Shared<dyn ReadSeek>,
     file_table_offset: u64,
+    file_table_size: u64,
     offset: u32,
     read_str: bool,
 ) -> Result<(FileInfo, String)> {
+    let info_size = std::mem::size_of::<FileInfo>() as u64;
+    check_table_entry("file", file_table_size, offset, info_size)?;
     reader
-        .lock()
-        .unwrap()
+        .lock_shared()
         .seek(SeekFrom::Start(file_table_offset + offset as u64))?;
     let file_info: FileInfo = reader_read_val(reader)?;
 
     let name = match read_str {
         true => {
-            let mut name_data = vec![0u8; file_info.name_len as usize];
-            reader.lock().unwrap().read_exact(&mut name_data)?;
-            String::from_utf8(name_data).unwrap()
+            check_table_entry(
+                "file",
+                file_table_size,
+                offset,
+                info_size + file_info.name_len as u64,
+            )?;
+            read_entry_name(reader, "file", offset, file_info.name_len)?
         }
         false => String::new(),
     };
     Ok((file_info, name))
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum DirEntry {
+    Dir(String),
+    // Name and size
+    File(String, usize),
+}
+
 pub struct RomFsDirectoryIterator {
     reader: Shared<dyn ReadSeek>,
     dir_table_offset: u64,
+    dir_table_size: u64,
     file_table_offset: u64,
+    file_table_size: u64,
     dir_offsets: Vec<u32>,
     file_offsets: Vec<u32>,
     cur_dir_idx: usize,
@@ -99,14 +170,18 @@ impl RomFsDirectoryIterator {
     pub fn new(
         reader: Shared<dyn ReadSeek>,
         dir_table_offset: u64,
+        dir_table_size: u64,
         file_table_offset: u64,
+        file_table_size: u64,
         dir_offsets: Vec<u32>,
         file_offsets: Vec<u32>,
     ) -> Self {
         Self {
             reader,
             dir_table_offset,
+            dir_table_size,
             file_table_offset,
+            file_table_size,
             dir_offsets,
             file_offsets,
             cur_dir_idx: 0,
@@ -114,19 +189,22 @@ impl RomFsDirectoryIterator {
         }
     }
 
-    pub fn next_dir(&mut self) -> Result<String> {
-        if self.cur_dir_idx == self.dir_offsets.len() {
-            Err(Error::new(ErrorKind::UnexpectedEof, "No more directories"))
-        } else {
-            let (_, dir_name) = read_dir_info(
-                &self.reader,
-                self.dir_table_offset,
-                self.dir_offsets[self.cur_dir_idx],
-                true,
-            )?;
-            self.cur_dir_idx += 1;
-            Ok(dir_name)
-        }
+    // None once every child directory was returned, errors are only for invalid tables
+    pub fn next_dir(&mut self) -> Result<Option<String>> {
+        let dir_offset = match self.dir_offsets.get(self.cur_dir_idx) {
+            Some(dir_offset) => *dir_offset,
+            None => return Ok(None),
+        };
+
+        let (_, dir_name) = read_dir_info(
+            &self.reader,
+            self.dir_table_offset,
+            self.dir_table_size,
+            dir_offset,
+            true,
+        )?;
+        self.cur_dir_idx += 1;
+        Ok(Some(dir_name))
     }
 
     pub fn get_dir_count(&self) -> usize {
@@ -137,19 +215,21 @@ impl RomFsDirectoryIterator {
         self.cur_dir_idx = 0;
     }
 
-    pub fn next_file(&mut self) -> Result<(String, usize)> {
-        if self.cur_file_idx == self.file_offsets.len() {
-            Err(Error::new(ErrorKind::UnexpectedEof, "No more files"))
-        } else {
-            let (file, file_name) = read_file_info(
-                &self.reader,
-                self.file_table_offset,
-                self.file_offsets[self.cur_file_idx],
-                true,
-            )?;
-            self.cur_file_idx += 1;
-            Ok((file_name, file.data_size))
-        }
+    pub fn next_file(&mut self) -> Result<Option<(String, usize)>> {
+        let file_offset = match self.file_offsets.get(self.cur_file_idx) {
+            Some(file_offset) => *file_offset,
+            None => return Ok(None),
+        };
+
+        let (file, file_name) = read_file_info(
+            &self.reader,
+            self.file_table_offset,
+            self.file_table_size,
+            file_offset,
+            true,
+        )?;
+        self.cur_file_idx += 1;
+        Ok(Some((file_name, file.data_size)))
     }
 
     pub fn get_file_count(&self) -> usize {
@@ -159,54 +239,436 @@ impl RomFsDirectoryIterator {
     pub fn rewind_files(&mut self) {
         self.cur_file_idx = 0;
     }
+
+    // Back to the first child directory and file
+    #[inline]
+    pub fn reset(&mut self) {
+        self.rewind_dirs();
+        self.rewind_files();
+    }
+
+    // Child directories first, then child files
+    pub fn next_entry(&mut self) -> Result<Option<DirEntry>> {
+        if let Some(dir_name) = self.next_dir()? {
+            return Ok(Some(DirEntry::Dir(dir_name)));
+        }
+
+        Ok(self
+            .next_file()?
+            .map(|(file_name, file_size)| DirEntry::File(file_name, file_size)))
+    }
+
+    // Both start over from the first child of their kind
+    pub fn dirs(&mut self) -> impl Iterator<Item = Result<String>> + '_ {
+        self.rewind_dirs();
+        std::iter::from_fn(move || self.next_dir().transpose())
+    }
+
+    pub fn files(&mut self) -> impl Iterator<Item = Result<(String, usize)>> + '_ {
+        self.rewind_files();
+        std::iter::from_fn(move || self.next_file().transpose())
+    }
+}
+
+// Files are plain ranges of the RomFs file data
+pub type RomFsFileReader = SubReader;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct IvfcLevel {
+    // Relative to the start of the section
+    pub offset: u64,
+    pub size: u64,
+    pub block_size: u64,
+}
+
+// Reads the last IVFC level (the actual RomFs), checking every block it touches against the
+// level hash tables, up to the master hash
+pub struct IvfcReader {
+    inner: Shared<dyn ReadSeek>,
+    levels: Vec<IvfcLevel>,
+    master_hash: [u8; 0x20],
+    verified_hash_blocks: HashMap<(usize, u64), Vec<u8>>,
+    read_offset: u64,
+}
+
+impl IvfcReader {
+    pub const HASH_SIZE: u64 = 0x20;
+    // Official ones use 0x4000, blocks are read (and padded) whole so bigger ones are refused
+    pub const MAX_BLOCK_SIZE: u64 = 0x100000;
+
+    pub(crate) fn check_levels(levels: &[IvfcLevel]) -> Result<()> {
+        let is_invalid_level = |level: &IvfcLevel| {
+            level.block_size == 0
+                || level.block_size > Self::MAX_BLOCK_SIZE
+                || level.offset.checked_add(level.size).is_none()
+        };
+        if levels.is_empty() || levels.iter().any(is_invalid_level) {
+            return Err(Error::InvalidInput(String::from("Invalid IVFC levels")));
+        }
+
+        Ok(())
+    }
+
+    pub fn new(
+        inner: Shared<dyn ReadSeek>,
+        levels: &[IvfcLevel],
+        master_hash: [u8; 0x20],
+    ) -> Result<Self> {
+        Self::check_levels(levels)?;
+
+        Ok(Self {
+            inner,
+            levels: levels.to_vec(),
+            master_hash,
+            verified_hash_blocks: HashMap::new(),
+            read_offset: 0,
+        })
+    }
+
+    fn read_verified_block(&mut self, level_idx: usize, block_idx: u64) -> io::Result<Vec<u8>> {
+        if let Some(block) = self.verified_hash_blocks.get(&(level_idx, block_idx)) {
+            return Ok(block.clone());
+        }
+
+        let level = self.levels[level_idx];
+        let block_offset = block_idx
+            .checked_mul(level.block_size)
+            .filter(|block_offset| *block_offset < level.size)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "IVFC level {} is too small for block {}",
+                        level_idx, block_idx
+                    ),
+                )
+            })?;
+        let block_size = std::cmp::min(level.block_size, level.size - block_offset);
+        let mut block = vec![0u8; block_size as usize];
+        {
+            let mut inner = self.inner.lock_shared();
+            inner.seek(SeekFrom::Start(level.offset + block_offset))?;
+            inner.read_exact(&mut block)?;
+        }
+
+        // Partial blocks are hashed as if they were padded with zeros
+        let mut hasher = Sha256::new();
+        hasher.update(&block);
+        hasher.update(vec![0u8; (level.block_size - block_size) as usize]);
+        let block_hash = hasher.finalize();
+
+        let hash_offset = block_idx * Self::HASH_SIZE;
+        let expected_hash = match level_idx {
+            0 => self
+                .master_hash
+                .get(hash_offset as usize..)
+                .map(|hash| hash.to_vec()),
+            _ => {
+                let parent_block_size = self.levels[level_idx - 1].block_size;
+                let parent_block =
+                    self.read_verified_block(level_idx - 1, hash_offset / parent_block_size)?;
+                parent_block
+                    .get((hash_offset % parent_block_size) as usize..)
+                    .map(|hash| hash.to_vec())
+            }
+        };
+
+        if !expected_hash.is_some_and(|hash| hash.starts_with(&block_hash)) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "IVFC level {} hash mismatch for block at offset 0x{:X}",
+                    level_idx, block_offset
+                ),
+            ));
+        }
+
+        // Data level blocks aren't cached, only the (much smaller) hash levels
+        if level_idx + 1 < self.levels.len() {
+            self.verified_hash_blocks
+                .insert((level_idx, block_idx), block.clone());
+        }
+        Ok(block)
+    }
+}
+
+impl Read for IvfcReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data_level_idx = self.levels.len() - 1;
+        let data_level = self.levels[data_level_idx];
+
+        let mut read_size = 0;
+        while (read_size < buf.len()) && (self.read_offset < data_level.size) {
+            let block_idx = self.read_offset / data_level.block_size;
+            let block = self.read_verified_block(data_level_idx, block_idx)?;
+
+            let block_read_offset = (self.read_offset % data_level.block_size) as usize;
+            let copy_size = std::cmp::min(buf.len() - read_size, block.len() - block_read_offset);
+            buf[read_size..read_size + copy_size]
+                .copy_from_slice(&block[block_read_offset..block_read_offset + copy_size]);
+
+            read_size += copy_size;
+            self.read_offset += copy_size as u64;
+        }
+
+        Ok(read_size)
+    }
+}
+
+impl Seek for IvfcReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let data_size = self.levels[self.levels.len() - 1].size;
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.read_offset as i64 + offset,
+            SeekFrom::End(offset) => data_size as i64 + offset,
+        };
+
+        if new_offset < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of data",
+            ));
+        }
+
+        self.read_offset = new_offset as u64;
+        Ok(self.read_offset)
+    }
+}
+
+// Turns "rom:/qwe//./b.txt", "/qwe/b.txt/" etc. into "qwe/b.txt" (the root being "")
+pub fn normalize_romfs_path(path: &str) -> Result<String> {
+    let path = path.strip_prefix("rom:").unwrap_or(path);
+
+    let mut path_items: Vec<&str> = Vec::new();
+    for item in path.split('/') {
+        match item {
+            "" | "." => {}
+            ".." => {
+                if path_items.pop().is_none() {
+                    return Err(Error::InvalidInput(format!(
+                        "Path escapes the RomFs root: '{}'",
+                        path
+                    )));
+                }
+            }
+            _ => path_items.push(item),
+        }
+    }
+
+    Ok(path_items.join("/"))
+}
+
+// `*` matches any run of characters and `?` a single one, both within the path item
+fn glob_match_item(pattern: &[char], name: &[char], case_insensitive: bool) -> bool {
+    match (pattern.first(), name.first()) {
+        (None, None) => true,
+        (Some('*'), _) => {
+            glob_match_item(&pattern[1..], name, case_insensitive)
+                || (!name.is_empty() && glob_match_item(pattern, &name[1..], case_insensitive))
+        }
+        (Some('?'), Some(_)) => glob_match_item(&pattern[1..], &name[1..], case_insensitive),
+        (Some(pattern_c), Some(name_c)) => {
+            let matches = match case_insensitive {
+                true => pattern_c.eq_ignore_ascii_case(name_c),
+                false => pattern_c == name_c,
+            };
+            matches && glob_match_item(&pattern[1..], &name[1..], case_insensitive)
+        }
+        _ => false,
+    }
+}
+
+// `**` matches any number (including none) of whole path items
+fn glob_match_path(pattern_items: &[&str], path_items: &[&str], case_insensitive: bool) -> bool {
+    match (pattern_items.first(), path_items.first()) {
+        (None, _) => path_items.is_empty(),
+        (Some(&"**"), _) => {
+            glob_match_path(&pattern_items[1..], path_items, case_insensitive)
+                || (!path_items.is_empty()
+                    && glob_match_path(pattern_items, &path_items[1..], case_insensitive))
+        }
+        (Some(pattern_item), Some(path_item)) => {
+            let pattern_chars: Vec<char> = pattern_item.chars().collect();
+            let name_chars: Vec<char> = path_item.chars().collect();
+            glob_match_item(&pattern_chars, &name_chars, case_insensitive)
+                && glob_match_path(&pattern_items[1..], &path_items[1..], case_insensitive)
+        }
+        (Some(_), None) => false,
+    }
+}
+
+fn join_path(parent_path: &str, name: &str) -> String {
+    if parent_path.is_empty() {
+        String::from(name)
+    } else {
+        format!("{}/{}", parent_path, name)
+    }
+}
+
+// Depth-first walk over every file, only keeping the pending directories in memory
+pub struct RomFsWalker {
+    reader: Shared<dyn ReadSeek>,
+    dir_table_offset: u64,
+    dir_table_size: u64,
+    file_table_offset: u64,
+    file_table_size: u64,
+    pending_dirs: Vec<(u32, String)>,
+    cur_dir_path: String,
+    cur_file_offset: u32,
+    visited_dir_offsets: HashSet<u32>,
+    visited_file_offsets: HashSet<u32>,
+}
+
+impl RomFsWalker {
+    pub fn new(
+        reader: Shared<dyn ReadSeek>,
+        dir_table_offset: u64,
+        dir_table_size: u64,
+        file_table_offset: u64,
+        file_table_size: u64,
+    ) -> Self {
+        Self {
+            reader,
+            dir_table_offset,
+            dir_table_size,
+            file_table_offset,
+            file_table_size,
+            pending_dirs: vec![(RomFs::ROOT_DIR_OFFSET, String::new())],
+            cur_dir_path: String::new(),
+            cur_file_offset: RomFs::INVALID_INFO_OFFSET,
+            visited_dir_offsets: HashSet::new(),
+            visited_file_offsets: HashSet::new(),
+        }
+    }
+
+    fn next_entry(&mut self) -> Result<Option<(String, usize)>> {
+        loop {
+            if self.cur_file_offset != RomFs::INVALID_INFO_OFFSET {
+                check_chain_offset(&mut self.visited_file_offsets, "file", self.cur_file_offset)?;
+                let (file, file_name) = read_file_info(
+                    &self.reader,
+                    self.file_table_offset,
+                    self.file_table_size,
+                    self.cur_file_offset,
+                    true,
+                )?;
+                self.cur_file_offset = file.sibling_file_offset;
+                return Ok(Some((
+                    join_path(&self.cur_dir_path, &file_name),
+                    file.data_size,
+                )));
+            }
+
+            let (dir_offset, parent_path) = match self.pending_dirs.pop() {
+                Some(pending_dir) => pending_dir,
+                None => return Ok(None),
+            };
+            check_chain_offset(&mut self.visited_dir_offsets, "directory", dir_offset)?;
+            let (dir, dir_name) = read_dir_info(
+                &self.reader,
+                self.dir_table_offset,
+                self.dir_table_size,
+                dir_offset,
+                true,
+            )?;
+            let dir_path = join_path(&parent_path, &dir_name);
+
+            // The sibling goes first so that the child subtree gets walked before it
+            if dir.sibling_dir_offset != RomFs::INVALID_INFO_OFFSET {
+                self.pending_dirs
+                    .push((dir.sibling_dir_offset, parent_path));
+            }
+            if dir.first_child_dir_offset != RomFs::INVALID_INFO_OFFSET {
+                self.pending_dirs
+                    .push((dir.first_child_dir_offset, dir_path.clone()));
+            }
+
+            self.cur_dir_path = dir_path;
+            self.cur_file_offset = dir.first_child_file_offset;
+        }
+    }
 }
 
+impl Iterator for RomFsWalker {
+    type Item = Result<(String, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entry = self.next_entry();
+        if entry.is_err() {
+            // Don't keep walking a corrupted filesystem
+            self.pending_dirs.clear();
+            self.cur_file_offset = RomFs::INVALID_INFO_OFFSET;
+        }
+        entry.transpose()
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct RomFsOptions {
+    // Path items are matched ignoring ASCII case. An exact (hashed) match is always preferred,
+    // otherwise the first case-insensitive match in the directory's child order is used
+    pub case_insensitive: bool,
+}
+
+// (Child directory names, child file names and sizes)
+pub type DirectoryListing = (Vec<String>, Vec<(String, usize)>);
+
 pub struct RomFs {
     reader: Shared<dyn ReadSeek>,
     header: Header,
+    options: RomFsOptions,
+    // File count and total size, computed on first use
+    totals: Option<(usize, usize)>,
 }
 
 impl RomFs {
     pub const INVALID_INFO_OFFSET: u32 = u32::MAX;
     const ROOT_DIR_OFFSET: u32 = 0;
 
-    fn compute_hash(parent_offset: u32, name: &[u8], hash_table_count: usize) -> u32 {
+    pub(crate) fn compute_hash(parent_offset: u32, name: &[u8], hash_table_count: usize) -> u32 {
         let mut hash = parent_offset ^ 123456789;
         for name_chr in name {
-            hash = (hash >> 5) | (hash << 27);
+            hash = hash.rotate_right(5);
             hash ^= *name_chr as u32;
         }
 
-        hash % hash_table_count as u32
+        (hash as usize % hash_table_count) as u32
     }
 
     fn read_dir_offset(&mut self, hash: u32) -> Result<u32> {
-        self.reader.lock().unwrap().seek(SeekFrom::Start(
+        self.reader.lock_shared().seek(SeekFrom::Start(
             self.header.dir_hash_table_offset + hash as u64 * std::mem::size_of::<u32>() as u64,
         ))?;
-        reader_read_val(&self.reader)
+        Ok(reader_read_val(&self.reader)?)
     }
 
     fn read_file_offset(&mut self, hash: u32) -> Result<u32> {
-        self.reader.lock().unwrap().seek(SeekFrom::Start(
+        self.reader.lock_shared().seek(SeekFrom::Start(
             self.header.file_hash_table_offset + hash as u64 * std::mem::size_of::<u32>() as u64,
         ))?;
-        reader_read_val(&self.reader)
+        Ok(reader_read_val(&self.reader)?)
     }
 
     fn find_dir_offset(&mut self, parent_dir_offset: u32, name: String) -> Result<u32> {
-        let hash = Self::compute_hash(
-            parent_dir_offset,
-            name.as_bytes(),
-            self.header.dir_hash_table_size / std::mem::size_of::<u32>(),
-        );
-        let first_dir_offset = self.read_dir_offset(hash)?;
+        let hash_table_count = self.header.dir_hash_table_size / std::mem::size_of::<u32>();
+        let first_dir_offset = match hash_table_count {
+            0 => Self::INVALID_INFO_OFFSET,
+            _ => self.read_dir_offset(Self::compute_hash(
+                parent_dir_offset,
+                name.as_bytes(),
+                hash_table_count,
+            ))?,
+        };
 
+        let mut visited_dir_offsets: HashSet<u32> = HashSet::new();
         let mut cur_dir_offset = first_dir_offset;
         while cur_dir_offset != Self::INVALID_INFO_OFFSET {
+            check_chain_offset(&mut visited_dir_offsets, "directory", cur_dir_offset)?;
             let (dir, dir_name) = read_dir_info(
                 &self.reader,
                 self.header.dir_table_offset,
+                self.header.dir_table_size as u64,
                 cur_dir_offset,
                 true,
             )?;
@@ -217,22 +679,56 @@ impl RomFs {
             cur_dir_offset = dir.next_dir_hash;
         }
 
-        Err(Error::new(ErrorKind::NotFound, "Directory not found"))
+        if self.options.case_insensitive {
+            let (parent_dir, _) = read_dir_info(
+                &self.reader,
+                self.header.dir_table_offset,
+                self.header.dir_table_size as u64,
+                parent_dir_offset,
+                false,
+            )?;
+
+            let mut visited_dir_offsets: HashSet<u32> = HashSet::new();
+            let mut cur_dir_offset = parent_dir.first_child_dir_offset;
+            while cur_dir_offset != Self::INVALID_INFO_OFFSET {
+                check_chain_offset(&mut visited_dir_offsets, "directory", cur_dir_offset)?;
+                let (dir, dir_name) = read_dir_info(
+                    &self.reader,
+                    self.header.dir_table_offset,
+                    self.header.dir_table_size as u64,
+                    cur_dir_offset,
+                    true,
+                )?;
+                if dir_name.eq_ignore_ascii_case(&name) {
+                    return Ok(cur_dir_offset);
+                }
+
+                cur_dir_offset = dir.sibling_dir_offset;
+            }
+        }
+
+        Err(Error::NotFound(String::from("RomFs directory")))
     }
 
     fn find_file_info(&mut self, parent_dir_offset: u32, name: String) -> Result<FileInfo> {
-        let hash = Self::compute_hash(
-            parent_dir_offset,
-            name.as_bytes(),
-            self.header.file_hash_table_size / std::mem::size_of::<u32>(),
-        );
-        let first_dir_offset = self.read_file_offset(hash)?;
+        let hash_table_count = self.header.file_hash_table_size / std::mem::size_of::<u32>();
+        let first_file_offset = match hash_table_count {
+            0 => Self::INVALID_INFO_OFFSET,
+            _ => self.read_file_offset(Self::compute_hash(
+                parent_dir_offset,
+                name.as_bytes(),
+                hash_table_count,
+            ))?,
+        };
 
-        let mut cur_file_offset = first_dir_offset;
+        let mut visited_file_offsets: HashSet<u32> = HashSet::new();
+        let mut cur_file_offset = first_file_offset;
         while cur_file_offset != Self::INVALID_INFO_OFFSET {
+            check_chain_offset(&mut visited_file_offsets, "file", cur_file_offset)?;
             let (file, file_name) = read_file_info(
                 &self.reader,
                 self.header.file_table_offset,
+                self.header.file_table_size as u64,
                 cur_file_offset,
                 true,
             )?;
@@ -243,10 +739,39 @@ impl RomFs {
             cur_file_offset = file.next_file_hash;
         }
 
-        Err(Error::new(ErrorKind::NotFound, "File not found"))
+        if self.options.case_insensitive {
+            let (parent_dir, _) = read_dir_info(
+                &self.reader,
+                self.header.dir_table_offset,
+                self.header.dir_table_size as u64,
+                parent_dir_offset,
+                false,
+            )?;
+
+            let mut visited_file_offsets: HashSet<u32> = HashSet::new();
+            let mut cur_file_offset = parent_dir.first_child_file_offset;
+            while cur_file_offset != Self::INVALID_INFO_OFFSET {
+                check_chain_offset(&mut visited_file_offsets, "file", cur_file_offset)?;
+                let (file, file_name) = read_file_info(
+                    &self.reader,
+                    self.header.file_table_offset,
+                    self.header.file_table_size as u64,
+                    cur_file_offset,
+                    true,
+                )?;
+                if file_name.eq_ignore_ascii_case(&name) {
+                    return Ok(file);
+                }
+
+                cur_file_offset = file.sibling_file_offset;
+            }
+        }
+
+        Err(Error::NotFound(String::from("RomFs file")))
     }
 
     fn find_file(&mut self, path: String) -> Result<FileInfo> {
+        let path = normalize_romfs_path(&path)?;
         let mut path_items: Vec<_> = path.split('/').collect();
         let file_item = path_items.pop().unwrap();
 
@@ -259,7 +784,11 @@ impl RomFs {
     }
 
     fn find_dir(&mut self, path: String) -> Result<DirectoryInfo> {
-        let path_items: Vec<_> = path.split('/').collect();
+        let path = normalize_romfs_path(&path)?;
+        let path_items: Vec<_> = match path.is_empty() {
+            true => Vec::new(),
+            false => path.split('/').collect(),
+        };
 
         let mut cur_dir_offset = Self::ROOT_DIR_OFFSET;
         for dir_item in path_items {
@@ -269,6 +798,7 @@ impl RomFs {
         let (dir, _) = read_dir_info(
             &self.reader,
             self.header.dir_table_offset,
+            self.header.dir_table_size as u64,
             cur_dir_offset,
             false,
         )?;
@@ -283,6 +813,22 @@ impl RomFs {
         self.find_dir(path).is_ok()
     }
 
+    pub fn list_dir(&mut self, path: String) -> Result<DirectoryListing> {
+        let mut dir_iter = self.open_dir_iterator(path)?;
+
+        let mut dirs: Vec<String> = Vec::with_capacity(dir_iter.get_dir_count());
+        while let Some(dir_name) = dir_iter.next_dir()? {
+            dirs.push(dir_name);
+        }
+
+        let mut files: Vec<(String, usize)> = Vec::with_capacity(dir_iter.get_file_count());
+        while let Some(file) = dir_iter.next_file()? {
+            files.push(file);
+        }
+
+        Ok((dirs, files))
+    }
+
     pub fn get_file_size(&mut self, path: String) -> Result<usize> {
         let file_info = self.find_file(path)?;
         Ok(file_info.data_size)
@@ -299,19 +845,56 @@ impl RomFs {
         offset: u64,
         buf: &mut [u8],
     ) -> Result<usize> {
-        let file_data_offset = self.header.file_data_offset + file_offset;
-        let read_offset = file_data_offset + offset;
+        let read_offset = self
+            .header
+            .file_data_offset
+            .checked_add(file_offset)
+            .and_then(|file_data_offset| file_data_offset.checked_add(offset))
+            .ok_or_else(|| Error::InvalidInput(format!("Invalid read offset 0x{:X}", offset)))?;
         self.reader
-            .lock()
-            .unwrap()
+            .lock_shared()
             .seek(SeekFrom::Start(read_offset))?;
-        self.reader.lock().unwrap().read(buf)
+        Ok(self.reader.lock_shared().read(buf)?)
     }
 
-    #[inline]
+    // Reads past the end of the file are cut short (instead of reading the next file's data)
     pub fn read_file(&mut self, path: String, offset: u64, buf: &mut [u8]) -> Result<usize> {
-        let file_offset = self.get_file_offset(path)?;
-        self.read_file_by_offset(file_offset, offset, buf)
+        let file_info = self.find_file(path)?;
+        let file_left = (file_info.data_size as u64)
+            .checked_sub(offset)
+            .ok_or_else(|| Error::InvalidInput(format!("Invalid read offset 0x{:X}", offset)))?;
+        let read_size = std::cmp::min(buf.len() as u64, file_left) as usize;
+        if read_size == 0 {
+            return Ok(0);
+        }
+        self.read_file_by_offset(file_info.data_offset, offset, &mut buf[..read_size])
+    }
+
+    pub fn get_file_reader(&mut self, path: String) -> Result<RomFsFileReader> {
+        let file_info = self.find_file(path)?;
+        let file_offset = self
+            .header
+            .file_data_offset
+            .checked_add(file_info.data_offset)
+            .ok_or_else(|| Error::InvalidData(String::from("Invalid RomFs file offset")))?;
+        Ok(RomFsFileReader::new(
+            self.reader.clone(),
+            file_offset,
+            file_info.data_size as u64,
+        ))
+    }
+
+    pub fn read_file_to_vec(&mut self, path: String) -> Result<Vec<u8>> {
+        let mut file_reader = self.get_file_reader(path)?;
+        let file_size = file_reader.get_size();
+        read_to_vec(&mut file_reader, file_size)
+    }
+
+    // SHA-256 of the file data, which is streamed instead of loaded
+    pub fn hash_file(&mut self, path: String) -> Result<[u8; 0x20]> {
+        let mut file_reader = self.get_file_reader(path)?;
+        let file_size = file_reader.get_size();
+        sha256_reader(&mut file_reader, file_size)
     }
 
     pub fn open_dir_iterator(&mut self, path: String) -> Result<RomFsDirectoryIterator> {
@@ -319,12 +902,15 @@ impl RomFs {
 
         let mut child_dir_offsets: Vec<u32> = Vec::new();
         let mut cur_child_dir_offset = dir.first_child_dir_offset;
+        let mut visited_dir_offsets: HashSet<u32> = HashSet::new();
         while cur_child_dir_offset != Self::INVALID_INFO_OFFSET {
+            check_chain_offset(&mut visited_dir_offsets, "directory", cur_child_dir_offset)?;
             child_dir_offsets.push(cur_child_dir_offset);
 
             let (child_dir, _) = read_dir_info(
                 &self.reader,
                 self.header.dir_table_offset,
+                self.header.dir_table_size as u64,
                 cur_child_dir_offset,
                 false,
             )?;
@@ -333,12 +919,15 @@ impl RomFs {
 
         let mut child_file_offsets: Vec<u32> = Vec::new();
         let mut cur_child_file_offset = dir.first_child_file_offset;
+        let mut visited_file_offsets: HashSet<u32> = HashSet::new();
         while cur_child_file_offset != Self::INVALID_INFO_OFFSET {
+            check_chain_offset(&mut visited_file_offsets, "file", cur_child_file_offset)?;
             child_file_offsets.push(cur_child_file_offset);
 
             let (child_file, _) = read_file_info(
                 &self.reader,
                 self.header.file_table_offset,
+                self.header.file_table_size as u64,
                 cur_child_file_offset,
                 false,
             )?;
@@ -348,15 +937,503 @@ impl RomFs {
         Ok(RomFsDirectoryIterator::new(
             self.reader.clone(),
             self.header.dir_table_offset,
+            self.header.dir_table_size as u64,
             self.header.file_table_offset,
+            self.header.file_table_size as u64,
             child_dir_offsets,
             child_file_offsets,
         ))
     }
 
+    fn extract_dir(
+        &mut self,
+        dir_path: &str,
+        out_dir: &Path,
+        progress_tracker: &mut ProgressTracker,
+    ) -> Result<()> {
+        create_dir_all(out_dir)?;
+
+        let mut dir_iter = self.open_dir_iterator(String::from(dir_path))?;
+        while let Some((file_name, _)) = dir_iter.next_file()? {
+            let file_path = join_path(dir_path, &file_name);
+            let mut out_file = File::create(out_dir.join(check_path_item(&file_name)?))?;
+            let mut file_reader = self.get_file_reader(file_path.clone())?;
+            let file_size = file_reader.get_size();
+            progress_tracker.copy(&file_path, &mut file_reader, &mut out_file, file_size)?;
+        }
+        while let Some(dir_name) = dir_iter.next_dir()? {
+            let out_sub_dir = out_dir.join(check_path_item(&dir_name)?);
+            self.extract_dir(
+                &join_path(dir_path, &dir_name),
+                &out_sub_dir,
+                progress_tracker,
+            )?;
+        }
+
+        Ok(())
+    }
+
+    #[inline]
+    pub fn extract_to(&mut self, out_dir: &Path) -> Result<()> {
+        self.extract_to_with_progress(out_dir, &mut |_| {})
+    }
+
+    pub fn extract_to_with_progress(
+        &mut self,
+        out_dir: &Path,
+        progress: &mut dyn FnMut(ProgressEvent),
+    ) -> Result<()> {
+        let total_size = self.get_total_size()? as u64;
+        let mut progress_tracker = ProgressTracker::new(progress, total_size);
+        self.extract_dir("", out_dir, &mut progress_tracker)
+    }
+
+    // Creates the output dirs, collecting the file ranges to copy
+    #[cfg(feature = "rayon")]
+    fn collect_extract_files(
+        &mut self,
+        dir_path: &str,
+        out_dir: &Path,
+        files: &mut Vec<(u64, u64, std::path::PathBuf)>,
+    ) -> Result<()> {
+        create_dir_all(out_dir)?;
+
+        let mut dir_iter = self.open_dir_iterator(String::from(dir_path))?;
+        while let Some((file_name, file_size)) = dir_iter.next_file()? {
+            let out_path = out_dir.join(check_path_item(&file_name)?);
+            let file_offset = self.get_file_offset(join_path(dir_path, &file_name))?;
+            let read_offset = self
+                .header
+                .file_data_offset
+                .checked_add(file_offset)
+                .ok_or_else(|| Error::InvalidData(String::from("Invalid RomFs file offset")))?;
+            files.push((read_offset, file_size as u64, out_path));
+        }
+        while let Some(dir_name) = dir_iter.next_dir()? {
+            let out_sub_dir = out_dir.join(check_path_item(&dir_name)?);
+            self.collect_extract_files(&join_path(dir_path, &dir_name), &out_sub_dir, files)?;
+        }
+
+        Ok(())
+    }
+
+    // open_reader must open the same data this RomFs was created from (once per worker, wrapped the
+    // same way, e.g. in an IvfcReader), for files that means reopening the path, since
+    // File::try_clone handles share the same cursor
+    #[cfg(feature = "rayon")]
+    pub fn extract_to_parallel<F>(&mut self, out_dir: &Path, open_reader: F) -> Result<()>
+    where
+        F: Fn() -> Result<Shared<dyn ReadSeek>> + Sync,
+    {
+        // Walking the tree first rejects looping tables, which the recursion below would never leave
+        self.get_file_count()?;
+        let mut files = Vec::new();
+        self.collect_extract_files("", out_dir, &mut files)?;
+        crate::util::extract_files_parallel(&files, open_reader)
+    }
+
+    // Full paths of the files matching the pattern (in walk order), which is always relative to the
+    // root, with or without a leading slash
+    pub fn glob(&mut self, pattern: &str) -> Result<Vec<String>> {
+        let pattern_items: Vec<&str> = pattern.split('/').filter(|item| !item.is_empty()).collect();
+
+        let mut paths: Vec<String> = Vec::new();
+        for entry in self.walk() {
+            let (path, _) = entry?;
+            let path_items: Vec<&str> = path.split('/').collect();
+            if glob_match_path(&pattern_items, &path_items, self.options.case_insensitive) {
+                paths.push(path);
+            }
+        }
+
+        Ok(paths)
+    }
+
+    fn get_totals(&mut self) -> Result<(usize, usize)> {
+        if let Some(totals) = self.totals {
+            return Ok(totals);
+        }
+
+        let mut totals = (0usize, 0usize);
+        for entry in self.walk() {
+            totals.0 += 1;
+            totals.1 = totals
+                .1
+                .checked_add(entry?.1)
+                .ok_or_else(|| Error::InvalidData(String::from("RomFs total size overflows")))?;
+        }
+        self.totals = Some(totals);
+        Ok(totals)
+    }
+
+    // Both walk the whole tree the first time (and only the first time) they are called
+    #[inline]
+    pub fn get_file_count(&mut self) -> Result<usize> {
+        Ok(self.get_totals()?.0)
+    }
+
+    #[inline]
+    pub fn get_total_size(&mut self) -> Result<usize> {
+        Ok(self.get_totals()?.1)
+    }
+
+    pub fn walk(&mut self) -> RomFsWalker {
+        RomFsWalker::new(
+            self.reader.clone(),
+            self.header.dir_table_offset,
+            self.header.dir_table_size as u64,
+            self.header.file_table_offset,
+            self.header.file_table_size as u64,
+        )
+    }
+
+    #[inline]
     pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        Self::new_with_options(reader, RomFsOptions::default())
+    }
+
+    #[inline]
+    pub fn open(path: impl AsRef<Path>) -> Result<Self> {
+        Self::new(new_shared(File::open(path)?))
+    }
+
+    // For untrusted in-memory data: never panics, and the whole tree is walked upfront so that
+    // malformed tables are reported here instead of on first use
+    pub fn try_new(data: &[u8]) -> Result<Self> {
+        let mut romfs = Self::new(new_shared_bytes(data.to_vec()))?;
+        romfs.get_file_count()?;
+        Ok(romfs)
+    }
+
+    // The reader is expected to start at the section start (where IVFC level offsets are relative to)
+    pub fn new_verified(
+        reader: Shared<dyn ReadSeek>,
+        ivfc_levels: &[IvfcLevel],
+        master_hash: [u8; 0x20],
+    ) -> Result<Self> {
+        let ivfc_reader = IvfcReader::new(reader, ivfc_levels, master_hash)?;
+        Self::new(new_shared(ivfc_reader))
+    }
+
+    pub fn new_with_options(reader: Shared<dyn ReadSeek>, options: RomFsOptions) -> Result<Self> {
         let header: Header = reader_read_val(&reader)?;
 
-        Ok(Self { reader, header })
+        // Entry offsets are added to their table offset from here on (entries being checked to be
+        // inside of their table), so the tables themselves must fit
+        let tables = [
+            (header.dir_hash_table_offset, header.dir_hash_table_size),
+            (header.dir_table_offset, header.dir_table_size),
+            (header.file_hash_table_offset, header.file_hash_table_size),
+            (header.file_table_offset, header.file_table_size),
+        ];
+        if tables
+            .iter()
+            .any(|(offset, size)| offset.checked_add(*size as u64).is_none())
+        {
+            return Err(Error::InvalidData(String::from(
+                "Invalid RomFs table range",
+            )));
+        }
+
+        Ok(Self {
+            reader,
+            header,
+            options,
+            totals: None,
+        })
+    }
+}
+
+struct BuilderDirectory {
+    name: String,
+    parent_idx: usize,
+    child_dir_idxs: Vec<usize>,
+    child_file_idxs: Vec<usize>,
+}
+
+struct BuilderFile {
+    name: String,
+    parent_idx: usize,
+    data: Vec<u8>,
+}
+
+// Builds the plain RomFs image (the IVFC level 6 data, what RomFs::new expects)
+pub struct RomFsBuilder {
+    dirs: Vec<BuilderDirectory>,
+    files: Vec<BuilderFile>,
+}
+
+impl RomFsBuilder {
+    const HEADER_SIZE: usize = std::mem::size_of::<Header>();
+    const FILE_DATA_OFFSET: usize = 0x200;
+    const FILE_DATA_ALIGNMENT: usize = 0x10;
+    const TABLE_ALIGNMENT: usize = 0x4;
+
+    pub fn new() -> Self {
+        Self {
+            // The root directory is always present, as the first directory entry
+            dirs: vec![BuilderDirectory {
+                name: String::new(),
+                parent_idx: 0,
+                child_dir_idxs: Vec::new(),
+                child_file_idxs: Vec::new(),
+            }],
+            files: Vec::new(),
+        }
+    }
+
+    fn find_child_dir(&self, parent_idx: usize, name: &str) -> Option<usize> {
+        self.dirs[parent_idx]
+            .child_dir_idxs
+            .iter()
+            .copied()
+            .find(|idx| self.dirs[*idx].name == name)
+    }
+
+    fn find_child_file(&self, parent_idx: usize, name: &str) -> Option<usize> {
+        self.dirs[parent_idx]
+            .child_file_idxs
+            .iter()
+            .copied()
+            .find(|idx| self.files[*idx].name == name)
+    }
+
+    fn ensure_dir(&mut self, path_items: &[&str]) -> Result<usize> {
+        let mut cur_dir_idx = 0;
+        for dir_item in path_items {
+            if self.find_child_file(cur_dir_idx, dir_item).is_some() {
+                return Err(Error::AlreadyExists(format!("file '{}'", dir_item)));
+            }
+
+            cur_dir_idx = match self.find_child_dir(cur_dir_idx, dir_item) {
+                Some(dir_idx) => dir_idx,
+                None => {
+                    self.dirs.push(BuilderDirectory {
+                        name: String::from(*dir_item),
+                        parent_idx: cur_dir_idx,
+                        child_dir_idxs: Vec::new(),
+                        child_file_idxs: Vec::new(),
+                    });
+                    let dir_idx = self.dirs.len() - 1;
+                    self.dirs[cur_dir_idx].child_dir_idxs.push(dir_idx);
+                    dir_idx
+                }
+            };
+        }
+
+        Ok(cur_dir_idx)
+    }
+
+    pub fn add_dir(&mut self, path: &str) -> Result<&mut Self> {
+        let path = normalize_romfs_path(path)?;
+        if !path.is_empty() {
+            let path_items: Vec<_> = path.split('/').collect();
+            self.ensure_dir(&path_items)?;
+        }
+
+        Ok(self)
+    }
+
+    pub fn add_file(&mut self, path: &str, data: Vec<u8>) -> Result<&mut Self> {
+        let path = normalize_romfs_path(path)?;
+        let mut path_items: Vec<_> = path.split('/').collect();
+        let file_item = path_items.pop().unwrap();
+        if file_item.is_empty() {
+            return Err(Error::InvalidInput(String::from("Empty file path")));
+        }
+
+        let parent_idx = self.ensure_dir(&path_items)?;
+        if self.find_child_file(parent_idx, file_item).is_some()
+            || self.find_child_dir(parent_idx, file_item).is_some()
+        {
+            return Err(Error::AlreadyExists(format!("'{}'", path)));
+        }
+
+        self.files.push(BuilderFile {
+            name: String::from(file_item),
+            parent_idx,
+            data,
+        });
+        let file_idx = self.files.len() - 1;
+        self.dirs[parent_idx].child_file_idxs.push(file_idx);
+
+        Ok(self)
+    }
+
+    fn get_hash_table_count(entry_count: usize) -> usize {
+        if entry_count < 3 {
+            3
+        } else if entry_count < 19 {
+            entry_count | 1
+        } else {
+            let mut count = entry_count;
+            while [2, 3, 5, 7, 11, 13, 17]
+                .iter()
+                .any(|prime| count.is_multiple_of(*prime))
+            {
+                count += 1;
+            }
+            count
+        }
+    }
+
+    pub fn write_to(self, mut out: impl Write) -> Result<()> {
+        let mut dir_offsets: Vec<u32> = Vec::with_capacity(self.dirs.len());
+        let mut cur_offset = 0;
+        for dir in self.dirs.iter() {
+            dir_offsets.push(cur_offset);
+            cur_offset += (std::mem::size_of::<DirectoryInfo>()
+                + align_up(dir.name.len(), Self::TABLE_ALIGNMENT)) as u32;
+        }
+
+        let mut file_offsets: Vec<u32> = Vec::with_capacity(self.files.len());
+        let mut file_data_offsets: Vec<u64> = Vec::with_capacity(self.files.len());
+        let mut cur_offset = 0;
+        let mut file_data_size = 0;
+        for file in self.files.iter() {
+            file_offsets.push(cur_offset);
+            cur_offset += (std::mem::size_of::<FileInfo>()
+                + align_up(file.name.len(), Self::TABLE_ALIGNMENT))
+                as u32;
+
+            file_data_offsets.push(file_data_size as u64);
+            file_data_size = align_up(file_data_size + file.data.len(), Self::FILE_DATA_ALIGNMENT);
+        }
+
+        let sibling_offset = |child_idxs: &[usize], idx: usize, offsets: &[u32]| {
+            let pos = child_idxs.iter().position(|child_idx| *child_idx == idx);
+            pos.and_then(|pos| child_idxs.get(pos + 1))
+                .map_or(RomFs::INVALID_INFO_OFFSET, |sibling_idx| {
+                    offsets[*sibling_idx]
+                })
+        };
+
+        let dir_bucket_count = Self::get_hash_table_count(self.dirs.len());
+        let mut dir_hash_table = vec![RomFs::INVALID_INFO_OFFSET; dir_bucket_count];
+        let mut dir_table: Vec<u8> = Vec::new();
+        for (idx, dir) in self.dirs.iter().enumerate() {
+            let parent_dir_offset = dir_offsets[dir.parent_idx];
+            let hash =
+                RomFs::compute_hash(parent_dir_offset, dir.name.as_bytes(), dir_bucket_count);
+            let next_dir_hash = dir_hash_table[hash as usize];
+            dir_hash_table[hash as usize] = dir_offsets[idx];
+
+            let sibling_dir_offset = match idx {
+                0 => RomFs::INVALID_INFO_OFFSET,
+                _ => sibling_offset(&self.dirs[dir.parent_idx].child_dir_idxs, idx, &dir_offsets),
+            };
+            let first_child_dir_offset = dir
+                .child_dir_idxs
+                .first()
+                .map_or(RomFs::INVALID_INFO_OFFSET, |child_idx| {
+                    dir_offsets[*child_idx]
+                });
+            let first_child_file_offset = dir
+                .child_file_idxs
+                .first()
+                .map_or(RomFs::INVALID_INFO_OFFSET, |child_idx| {
+                    file_offsets[*child_idx]
+                });
+
+            for value in [
+                parent_dir_offset,
+                sibling_dir_offset,
+                first_child_dir_offset,
+                first_child_file_offset,
+                next_dir_hash,
+                dir.name.len() as u32,
+            ] {
+                dir_table.extend_from_slice(&value.to_le_bytes());
+            }
+            dir_table.extend_from_slice(dir.name.as_bytes());
+            dir_table.resize(align_up(dir_table.len(), Self::TABLE_ALIGNMENT), 0);
+        }
+
+        let file_bucket_count = Self::get_hash_table_count(self.files.len());
+        let mut file_hash_table = vec![RomFs::INVALID_INFO_OFFSET; file_bucket_count];
+        let mut file_table: Vec<u8> = Vec::new();
+        for (idx, file) in self.files.iter().enumerate() {
+            let parent_dir_offset = dir_offsets[file.parent_idx];
+            let hash =
+                RomFs::compute_hash(parent_dir_offset, file.name.as_bytes(), file_bucket_count);
+            let next_file_hash = file_hash_table[hash as usize];
+            file_hash_table[hash as usize] = file_offsets[idx];
+
+            let sibling_file_offset = sibling_offset(
+                &self.dirs[file.parent_idx].child_file_idxs,
+                idx,
+                &file_offsets,
+            );
+
+            file_table.extend_from_slice(&parent_dir_offset.to_le_bytes());
+            file_table.extend_from_slice(&sibling_file_offset.to_le_bytes());
+            file_table.extend_from_slice(&file_data_offsets[idx].to_le_bytes());
+            file_table.extend_from_slice(&(file.data.len() as u64).to_le_bytes());
+            file_table.extend_from_slice(&next_file_hash.to_le_bytes());
+            file_table.extend_from_slice(&(file.name.len() as u32).to_le_bytes());
+            file_table.extend_from_slice(file.name.as_bytes());
+            file_table.resize(align_up(file_table.len(), Self::TABLE_ALIGNMENT), 0);
+        }
+
+        let dir_hash_table: Vec<u8> = dir_hash_table
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect();
+        let file_hash_table: Vec<u8> = file_hash_table
+            .iter()
+            .flat_map(|offset| offset.to_le_bytes())
+            .collect();
+
+        let dir_hash_table_offset = align_up(
+            Self::FILE_DATA_OFFSET + file_data_size,
+            Self::TABLE_ALIGNMENT,
+        );
+        let dir_table_offset = dir_hash_table_offset + dir_hash_table.len();
+        let file_hash_table_offset = dir_table_offset + dir_table.len();
+        let file_table_offset = file_hash_table_offset + file_hash_table.len();
+
+        let mut header_data: Vec<u8> = Vec::with_capacity(Self::FILE_DATA_OFFSET);
+        for value in [
+            Self::HEADER_SIZE,
+            dir_hash_table_offset,
+            dir_hash_table.len(),
+            dir_table_offset,
+            dir_table.len(),
+            file_hash_table_offset,
+            file_hash_table.len(),
+            file_table_offset,
+            file_table.len(),
+            Self::FILE_DATA_OFFSET,
+        ] {
+            header_data.extend_from_slice(&(value as u64).to_le_bytes());
+        }
+        header_data.resize(Self::FILE_DATA_OFFSET, 0);
+        out.write_all(&header_data)?;
+
+        let mut written_data_size = 0;
+        for file in self.files.iter() {
+            out.write_all(&file.data)?;
+            written_data_size += file.data.len();
+
+            let padding_size =
+                align_up(written_data_size, Self::FILE_DATA_ALIGNMENT) - written_data_size;
+            out.write_all(&vec![0u8; padding_size])?;
+            written_data_size += padding_size;
+        }
+        let padding_size = dir_hash_table_offset - (Self::FILE_DATA_OFFSET + written_data_size);
+        out.write_all(&vec![0u8; padding_size])?;
+
+        out.write_all(&dir_hash_table)?;
+        out.write_all(&dir_table)?;
+        out.write_all(&file_hash_table)?;
+        out.write_all(&file_table)?;
+
+        Ok(())
+    }
+}
+
+impl Default for RomFsBuilder {
+    fn default() -> Self {
+        Self::new()
     }
 }
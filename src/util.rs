@@ -1,26 +1,116 @@
+use crate::error::{Error, Result};
 use aes::Aes128;
 use ctr::cipher::NewStreamCipher;
 use ctr::cipher::StreamCipher;
 use ctr::Ctr128;
-use std::io::{Read, Result, Seek, SeekFrom};
-use std::sync::{Arc, Mutex};
+use sha2::{Digest, Sha256};
+use std::collections::VecDeque;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use xts_mode::Xts128;
 
 pub type Shared<T> = Arc<Mutex<T>>;
 
-pub trait ReadSeek: Read + Seek + Send + Sync {}
+// Readers are always seeked before being read, so a panic while one is locked doesn't leave it in a
+// broken state. Poisoning is ignored instead of making every later access panic as well
+pub trait LockShared<T: ?Sized> {
+    fn lock_shared(&self) -> MutexGuard<'_, T>;
+}
+
+impl<T: ?Sized> LockShared<T> for Mutex<T> {
+    #[inline]
+    fn lock_shared(&self) -> MutexGuard<'_, T> {
+        self.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+pub trait ReadSeek: Read + Seek + Send + Sync {
+    // Total size of the data, the current position is kept as-is
+    fn len(&mut self) -> io::Result<u64> {
+        let offset = self.stream_position()?;
+        let size = self.seek(SeekFrom::End(0))?;
+        if offset != size {
+            self.seek(SeekFrom::Start(offset))?;
+        }
+        Ok(size)
+    }
+
+    #[inline]
+    fn is_empty(&mut self) -> io::Result<bool> {
+        Ok(self.len()? == 0)
+    }
+}
 impl<R: Read + Seek + Send + Sync> ReadSeek for R {}
 
-pub fn reader_read_val<T>(reader: &Shared<dyn ReadSeek>) -> Result<T> {
+// Async counterpart of ReadSeek, for sources like network streams
+#[cfg(feature = "tokio")]
+pub trait AsyncReadSeek: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + Send {}
+#[cfg(feature = "tokio")]
+impl<R: tokio::io::AsyncRead + tokio::io::AsyncSeek + Unpin + Send> AsyncReadSeek for R {}
+
+pub fn reader_read_val<T>(reader: &Shared<dyn ReadSeek>) -> io::Result<T> {
     let mut t: T = unsafe { std::mem::zeroed() };
 
     let t_buf = unsafe {
         std::slice::from_raw_parts_mut(&mut t as *mut _ as *mut u8, std::mem::size_of::<T>())
     };
-    reader.lock().unwrap().read_exact(t_buf)?;
+    reader.lock_shared().read_exact(t_buf)?;
 
     Ok(t)
 }
 
+// Sizes come from untrusted headers, so the table is read as it comes instead of allocated upfront
+pub(crate) fn reader_read_string_table(
+    reader: &Shared<dyn ReadSeek>,
+    size: u32,
+) -> Result<Vec<u8>> {
+    let mut string_table: Vec<u8> = Vec::new();
+    let mut reader = reader.lock_shared();
+    (&mut *reader)
+        .take(size as u64)
+        .read_to_end(&mut string_table)?;
+    if string_table.len() != size as usize {
+        return Err(Error::InvalidData(format!(
+            "Truncated string table (expected 0x{:X} bytes, found 0x{:X})",
+            size,
+            string_table.len()
+        )));
+    }
+
+    Ok(string_table)
+}
+
+// Same for any other data whose size comes from a header
+pub(crate) fn read_to_vec(reader: &mut (impl Read + ?Sized), size: u64) -> Result<Vec<u8>> {
+    let mut data: Vec<u8> = Vec::new();
+    reader.take(size).read_to_end(&mut data)?;
+    if data.len() as u64 != size {
+        return Err(Error::UnexpectedEof(format!(
+            "Expected 0x{:X} bytes, found 0x{:X}",
+            size,
+            data.len()
+        )));
+    }
+
+    Ok(data)
+}
+
+// Names are NUL-terminated, the last one may also end with the table itself
+pub(crate) fn get_string_table_name(string_table: &[u8], offset: u32) -> Result<String> {
+    let str_t = string_table
+        .get(offset as usize..)
+        .ok_or_else(|| Error::InvalidData(format!("Invalid string table offset 0x{:X}", offset)))?;
+    let name_len = str_t.iter().position(|c| *c == 0).unwrap_or(str_t.len());
+
+    String::from_utf8(str_t[..name_len].to_vec()).map_err(|_| {
+        Error::InvalidData(format!(
+            "Invalid file name at string table offset 0x{:X}",
+            offset
+        ))
+    })
+}
+
+// In-memory reader, for data which is already loaded (or downloaded) instead of being on a file
 pub struct DataReader {
     offset: usize,
     data: Vec<u8>,
@@ -30,33 +120,285 @@ impl DataReader {
     pub fn new(data: Vec<u8>) -> Self {
         Self { offset: 0, data }
     }
+
+    #[inline]
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
 }
 
 impl Read for DataReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let end = std::cmp::min(self.data.len(), self.offset + buf.len());
-        let size = end - self.offset;
-        buf.copy_from_slice(&self.data[self.offset..end]);
-        self.offset = end;
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        // Reading at (or past) the end just reads nothing
+        let start = std::cmp::min(self.data.len(), self.offset);
+        let end = std::cmp::min(self.data.len(), start + buf.len());
+        let size = end - start;
+        buf[..size].copy_from_slice(&self.data[start..end]);
+        self.offset += size;
         Ok(size)
     }
 }
 
 impl Seek for DataReader {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        match pos {
-            SeekFrom::Current(pos_val) => {
-                let new_offset = self.offset as i64 + pos_val;
-                self.offset = new_offset as usize;
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+            SeekFrom::End(offset) => self.data.len() as i64 + offset,
+        };
+
+        if new_offset < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of data",
+            ));
+        }
+
+        self.offset = new_offset as usize;
+        Ok(self.offset as u64)
+    }
+}
+
+// Reads a file through a read-only memory map, so reads are plain copies instead of syscalls. The file
+// is expected to not be modified (or truncated) while mapped
+#[cfg(feature = "memmap2")]
+pub struct MmapReader {
+    offset: u64,
+    mmap: memmap2::Mmap,
+}
+
+#[cfg(feature = "memmap2")]
+impl MmapReader {
+    pub fn new(file: &std::fs::File) -> io::Result<Self> {
+        let mmap = unsafe { memmap2::Mmap::map(file)? };
+        Ok(Self { offset: 0, mmap })
+    }
+
+    #[inline]
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> io::Result<Self> {
+        Self::new(&std::fs::File::open(path)?)
+    }
+}
+
+#[cfg(feature = "memmap2")]
+impl Read for MmapReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let data_len = self.mmap.len() as u64;
+        let start = std::cmp::min(data_len, self.offset) as usize;
+        let end = std::cmp::min(self.mmap.len(), start.saturating_add(buf.len()));
+        let size = end - start;
+        buf[..size].copy_from_slice(&self.mmap[start..end]);
+        self.offset += size as u64;
+        Ok(size)
+    }
+}
+
+#[cfg(feature = "memmap2")]
+impl Seek for MmapReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.offset.checked_add_signed(offset),
+            SeekFrom::End(offset) => (self.mmap.len() as u64).checked_add_signed(offset),
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(new_offset)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of data",
+            )),
+        }
+    }
+}
+
+// Zero-based view of the [base_offset, base_offset + size) range of a reader, like a file within a
+// container or a section within an (already decrypted) NCA. Seeking past the end of the range fails
+pub struct SubReader {
+    inner: Shared<dyn ReadSeek>,
+    base_offset: u64,
+    read_offset: u64,
+    size: u64,
+}
+
+impl SubReader {
+    pub fn new(inner: Shared<dyn ReadSeek>, base_offset: u64, size: u64) -> Self {
+        Self {
+            inner,
+            base_offset,
+            read_offset: 0,
+            size,
+        }
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+
+    #[inline]
+    pub fn get_base_offset(&self) -> u64 {
+        self.base_offset
+    }
+}
+
+impl Read for SubReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.read_offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let read_size = std::cmp::min(buf.len() as u64, self.size - self.read_offset) as usize;
+
+        let inner_offset = self
+            .base_offset
+            .checked_add(self.read_offset)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "Invalid read offset"))?;
+        let mut inner = self.inner.lock_shared();
+        inner.seek(SeekFrom::Start(inner_offset))?;
+        let read_size = inner.read(&mut buf[..read_size])?;
+        self.read_offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for SubReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.read_offset as i64 + offset,
+            SeekFrom::End(offset) => self.size as i64 + offset,
+        };
+
+        if new_offset < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of data",
+            ));
+        }
+        if new_offset as u64 > self.size {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek past end of data",
+            ));
+        }
+
+        self.read_offset = new_offset as u64;
+        Ok(self.read_offset)
+    }
+}
+
+// Serves reads from an LRU cache of aligned blocks, so that lots of small reads (like the ones
+// done when walking a RomFs) don't end up as lots of small reads/seeks on the inner reader
+pub struct CachingReader {
+    inner: Shared<dyn ReadSeek>,
+    block_size: u64,
+    max_block_count: usize,
+    // Most recently used blocks are at the back
+    blocks: VecDeque<(u64, Vec<u8>)>,
+    offset: u64,
+}
+
+impl CachingReader {
+    pub const DEFAULT_BLOCK_SIZE: u64 = 0x8000;
+    pub const DEFAULT_BLOCK_COUNT: usize = 0x40;
+    // Blocks are read (and allocated) whole, so bigger ones are clamped to this
+    pub const MAX_BLOCK_SIZE: u64 = 0x100000;
+
+    #[inline]
+    pub fn new(inner: Shared<dyn ReadSeek>) -> Self {
+        Self::with_capacity(inner, Self::DEFAULT_BLOCK_SIZE, Self::DEFAULT_BLOCK_COUNT)
+    }
+
+    // The cache always holds at least one block of at least one byte, and at most MAX_BLOCK_SIZE
+    pub fn with_capacity(
+        inner: Shared<dyn ReadSeek>,
+        block_size: u64,
+        max_block_count: usize,
+    ) -> Self {
+        Self {
+            inner,
+            block_size: block_size.clamp(1, Self::MAX_BLOCK_SIZE),
+            max_block_count: max_block_count.max(1),
+            blocks: VecDeque::new(),
+            offset: 0,
+        }
+    }
+
+    fn get_block(&mut self, block_idx: u64) -> io::Result<&[u8]> {
+        if let Some(pos) = self.blocks.iter().position(|(idx, _)| *idx == block_idx) {
+            let block = self.blocks.remove(pos).unwrap();
+            self.blocks.push_back(block);
+        } else {
+            // The last block of the data might be a short one
+            let mut block_data = vec![0u8; self.block_size as usize];
+            let mut inner = self.inner.lock_shared();
+            inner.seek(SeekFrom::Start(block_idx * self.block_size))?;
+            let mut read_size = 0;
+            while read_size < block_data.len() {
+                match inner.read(&mut block_data[read_size..]) {
+                    Ok(0) => break,
+                    Ok(size) => read_size += size,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
             }
-            SeekFrom::Start(pos_val) => self.offset = pos_val as usize,
-            SeekFrom::End(pos_val) => {
-                let new_offset = self.data.len() as i64 + pos_val;
-                self.offset = new_offset as usize;
+            block_data.truncate(read_size);
+
+            if self.blocks.len() >= self.max_block_count {
+                self.blocks.pop_front();
+            }
+            self.blocks.push_back((block_idx, block_data));
+        }
+
+        Ok(&self.blocks.back().unwrap().1)
+    }
+}
+
+impl Read for CachingReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read_size = 0;
+        while read_size < buf.len() {
+            let block_idx = self.offset / self.block_size;
+            let block_offset = (self.offset % self.block_size) as usize;
+            let block = self.get_block(block_idx)?;
+            if block_offset >= block.len() {
+                break;
+            }
+
+            let copy_size = std::cmp::min(buf.len() - read_size, block.len() - block_offset);
+            buf[read_size..read_size + copy_size]
+                .copy_from_slice(&block[block_offset..block_offset + copy_size]);
+            read_size += copy_size;
+            self.offset += copy_size as u64;
+        }
+
+        Ok(read_size)
+    }
+}
+
+impl Seek for CachingReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => offset as i64,
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+            SeekFrom::End(offset) => {
+                self.inner.lock_shared().seek(SeekFrom::End(0))? as i64 + offset
             }
         };
 
-        Ok(self.offset as u64)
+        if new_offset < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of data",
+            ));
+        }
+
+        self.offset = new_offset as u64;
+        Ok(self.offset)
     }
 }
 
@@ -64,34 +406,88 @@ pub fn get_nintendo_tweak(sector_index: u128) -> [u8; 0x10] {
     sector_index.to_be_bytes()
 }
 
+// AES-CTR as done for NCA sections, `offset` being the absolute offset of the data (no need for it
+// to be aligned), since the counter's lower half is the index of the 0x10 block within the NCA
+pub fn aes128_ctr_crypt(data: &mut [u8], offset: u64, ctr: u64, key: &[u8; 0x10]) {
+    let aligned_offset = align_down(offset, 0x10);
+    let iv = get_nintendo_tweak(((aligned_offset as u128) >> 4) | ((ctr as u128) << 64));
+    let mut cipher = Ctr128::<Aes128>::new(key.into(), &iv.into());
+
+    // Skip the keystream before the actual offset
+    let mut skipped = [0u8; 0x10];
+    cipher.encrypt(&mut skipped[..(offset - aligned_offset) as usize]);
+    cipher.encrypt(data);
+}
+
 pub struct Aes128CtrReader {
     base_offset: u64,
     offset: u64,
     base_reader: Shared<dyn ReadSeek>,
     ctr: u64,
     key: Vec<u8>,
+    // Read-ahead of decrypted data (disabled if the size is 0), as (absolute offset, data)
+    buffer_size: usize,
+    buffer: (u64, Vec<u8>),
 }
 
 impl Aes128CtrReader {
+    #[inline]
     pub fn new(
         base_reader: Shared<dyn ReadSeek>,
         base_offset: u64,
         ctr: u64,
         key: Vec<u8>,
     ) -> Self {
-        base_reader
-            .lock()
-            .unwrap()
-            .seek(SeekFrom::Start(base_offset))
-            .unwrap();
+        Self::with_buffer(base_reader, base_offset, ctr, key, 0)
+    }
+
+    // Reads smaller than the buffer are served from a decrypted read-ahead buffer, so that lots of small
+    // reads (like the RomFs ones) don't each need to lock, seek and read the base reader
+    pub fn with_buffer(
+        base_reader: Shared<dyn ReadSeek>,
+        base_offset: u64,
+        ctr: u64,
+        key: Vec<u8>,
+        buffer_size: usize,
+    ) -> Self {
         Self {
             base_offset,
             offset: base_offset,
             base_reader,
             ctr,
             key,
+            buffer_size: align_up(buffer_size, 0x10),
+            buffer: (0, Vec::new()),
         }
     }
+
+    #[inline]
+    pub fn get_buffer_size(&self) -> usize {
+        self.buffer_size
+    }
+
+    // Reads and decrypts data at the (absolute) aligned offset, returning how much could be read
+    fn read_decrypted(&self, aligned_offset: u64, buf: &mut [u8]) -> io::Result<usize> {
+        let mut read_size = 0;
+        {
+            let mut base_reader = self.base_reader.lock_shared();
+            base_reader.seek(SeekFrom::Start(aligned_offset))?;
+            while read_size < buf.len() {
+                match base_reader.read(&mut buf[read_size..]) {
+                    Ok(0) => break,
+                    Ok(size) => read_size += size,
+                    Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                    Err(err) => return Err(err),
+                }
+            }
+        }
+
+        let iv = get_nintendo_tweak(((aligned_offset as u128) >> 4) | ((self.ctr as u128) << 64));
+        let mut ctr = Ctr128::<Aes128>::new_var(&self.key, &iv)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err.to_string()))?;
+        ctr.decrypt(&mut buf[..read_size]);
+        Ok(read_size)
+    }
 }
 
 pub const fn align_down(value: u64, align: u64) -> u64 {
@@ -105,49 +501,275 @@ pub const fn align_up(value: usize, align: usize) -> usize {
 }
 
 impl Read for Aes128CtrReader {
-    fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
-        let offset = self.base_reader.lock().unwrap().stream_position()?;
-        let aligned_offset = align_down(offset, 0x10);
-        let diff = (offset - aligned_offset) as i64;
-
-        let read_buf_size_raw = buf.len() + diff as usize;
-        let read_buf_size = align_up(read_buf_size_raw, 0x10);
-        let read_buf_size_diff = (read_buf_size - read_buf_size_raw) as i64;
-        let mut read_buf = vec![0u8; read_buf_size];
-        self.seek(SeekFrom::Current(-diff))?;
-        let read_size = self.base_reader.lock().unwrap().read(&mut read_buf)? as i64;
-        self.seek(SeekFrom::Current(read_size - read_buf_size_diff))?;
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
 
-        let iv = get_nintendo_tweak(((aligned_offset as u128) >> 4) | ((self.ctr as u128) << 64));
-        let mut ctr = Ctr128::<Aes128>::new_var(&self.key, &iv).unwrap();
-        ctr.decrypt(&mut read_buf);
+        let offset = self.offset;
+        let read_size = if buf.len() < self.buffer_size {
+            let (buffer_offset, buffer_data) = &self.buffer;
+            let in_buffer =
+                (*buffer_offset..*buffer_offset + buffer_data.len() as u64).contains(&offset);
+            if !in_buffer {
+                let aligned_offset = align_down(offset, 0x10);
+                let mut buffer_data = std::mem::take(&mut self.buffer.1);
+                buffer_data.resize(self.buffer_size, 0);
+                let read_size = self.read_decrypted(aligned_offset, &mut buffer_data)?;
+                buffer_data.truncate(read_size);
+                self.buffer = (aligned_offset, buffer_data);
+            }
 
-        let read_buf_start = diff as usize;
-        let read_buf_end = read_buf_start + buf.len();
-        buf.copy_from_slice(&read_buf[read_buf_start..read_buf_end]);
+            let (buffer_offset, buffer_data) = &self.buffer;
+            let start = std::cmp::min((offset - buffer_offset) as usize, buffer_data.len());
+            let read_size = std::cmp::min(buf.len(), buffer_data.len() - start);
+            buf[..read_size].copy_from_slice(&buffer_data[start..start + read_size]);
+            read_size
+        } else {
+            let aligned_offset = align_down(offset, 0x10);
+            let diff = (offset - aligned_offset) as usize;
+            let mut read_buf = vec![0u8; align_up(buf.len() + diff, 0x10)];
+            let read_size = self.read_decrypted(aligned_offset, &mut read_buf)?;
+            let read_size = std::cmp::min(buf.len(), read_size.saturating_sub(diff));
+            buf[..read_size].copy_from_slice(&read_buf[diff..diff + read_size]);
+            read_size
+        };
 
-        Ok(buf.len())
+        self.offset += read_size as u64;
+        Ok(read_size)
     }
 }
 
 impl Seek for Aes128CtrReader {
-    fn seek(&mut self, pos: SeekFrom) -> Result<u64> {
-        match pos {
-            SeekFrom::Current(cur_pos) => {
-                let new_offset = self.offset as i64 + cur_pos;
-                self.offset = new_offset as u64;
+    // Offsets are relative to the base offset, and the end is the one of the base reader. Reads always
+    // seek the base reader themselves, so it's only touched here to find its end
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => self.base_offset as i64 + offset as i64,
+            SeekFrom::Current(offset) => self.offset as i64 + offset,
+            SeekFrom::End(offset) => {
+                self.base_reader.lock_shared().seek(SeekFrom::End(0))? as i64 + offset
             }
-            SeekFrom::Start(start_pos) => self.offset = self.base_offset + start_pos,
-            SeekFrom::End(end_pos) => {
-                let new_offset = self.offset as i64 + end_pos;
-                self.offset = new_offset as u64;
+        };
+
+        if new_offset < self.base_offset as i64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of section",
+            ));
+        }
+
+        self.offset = new_offset as u64;
+        Ok(self.offset - self.base_offset)
+    }
+}
+
+// AES-XTS reader over the [base_offset, base_offset + size) range, the sector at the base offset
+// having the given index (0 for old NCA sections). Reads decrypt all the sectors they touch
+pub struct Aes128XtsReader {
+    base_reader: Shared<dyn ReadSeek>,
+    base_offset: u64,
+    size: u64,
+    // Relative to the base offset
+    offset: u64,
+    xts: Xts128<Aes128>,
+    sector_size: usize,
+    first_sector_index: u64,
+}
+
+impl Aes128XtsReader {
+    pub fn new(
+        base_reader: Shared<dyn ReadSeek>,
+        base_offset: u64,
+        size: u64,
+        xts: Xts128<Aes128>,
+        sector_size: usize,
+        first_sector_index: u64,
+    ) -> Self {
+        Self {
+            base_reader,
+            base_offset,
+            size,
+            offset: 0,
+            xts,
+            sector_size,
+            first_sector_index,
+        }
+    }
+
+    #[inline]
+    pub fn get_size(&self) -> u64 {
+        self.size
+    }
+}
+
+impl Read for Aes128XtsReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.offset >= self.size || buf.is_empty() {
+            return Ok(0);
+        }
+
+        let read_size = std::cmp::min(buf.len() as u64, self.size - self.offset) as usize;
+        let sector_index = self.offset / self.sector_size as u64;
+        let aligned_offset = sector_index * self.sector_size as u64;
+        let diff = (self.offset - aligned_offset) as usize;
+        let mut sectors = vec![0u8; align_up(diff + read_size, self.sector_size)];
+        {
+            let mut base_reader = self.base_reader.lock_shared();
+            base_reader.seek(SeekFrom::Start(self.base_offset + aligned_offset))?;
+            base_reader.read_exact(&mut sectors)?;
+        }
+
+        self.xts.decrypt_area(
+            &mut sectors,
+            self.sector_size,
+            (self.first_sector_index + sector_index) as u128,
+            get_nintendo_tweak,
+        );
+        buf[..read_size].copy_from_slice(&sectors[diff..diff + read_size]);
+        self.offset += read_size as u64;
+        Ok(read_size)
+    }
+}
+
+impl Seek for Aes128XtsReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let new_offset = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.offset.checked_add_signed(offset),
+            SeekFrom::End(offset) => self.size.checked_add_signed(offset),
+        };
+
+        match new_offset {
+            Some(new_offset) => {
+                self.offset = new_offset;
+                Ok(new_offset)
             }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of section",
+            )),
         }
+    }
+}
+
+// Async version of Aes128CtrReader, the I/O is awaited while decryption is done in place
+#[cfg(feature = "tokio")]
+pub struct AsyncAes128CtrReader<R: AsyncReadSeek> {
+    inner: R,
+    base_offset: u64,
+    // Relative to the base offset
+    offset: u64,
+    ctr: u64,
+    key: [u8; 0x10],
+    // The inner reader still needs to be moved to the current offset
+    needs_seek: bool,
+    seek_in_progress: bool,
+}
 
-        self.base_reader
-            .lock()
-            .unwrap()
-            .seek(SeekFrom::Start(self.offset))
+#[cfg(feature = "tokio")]
+impl<R: AsyncReadSeek> AsyncAes128CtrReader<R> {
+    pub fn new(inner: R, base_offset: u64, ctr: u64, key: [u8; 0x10]) -> Self {
+        Self {
+            inner,
+            base_offset,
+            offset: 0,
+            ctr,
+            key,
+            needs_seek: true,
+            seek_in_progress: false,
+        }
+    }
+
+    #[inline]
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncReadSeek> tokio::io::AsyncRead for AsyncAes128CtrReader<R> {
+    fn poll_read(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut tokio::io::ReadBuf<'_>,
+    ) -> std::task::Poll<io::Result<()>> {
+        use std::task::ready;
+
+        let this = self.get_mut();
+        if this.needs_seek {
+            if !this.seek_in_progress {
+                std::pin::Pin::new(&mut this.inner)
+                    .start_seek(SeekFrom::Start(this.base_offset + this.offset))?;
+                this.seek_in_progress = true;
+            }
+            ready!(std::pin::Pin::new(&mut this.inner).poll_complete(cx))?;
+            this.seek_in_progress = false;
+            this.needs_seek = false;
+        }
+
+        let filled_size = buf.filled().len();
+        ready!(std::pin::Pin::new(&mut this.inner).poll_read(cx, buf))?;
+        let read_data = &mut buf.filled_mut()[filled_size..];
+        aes128_ctr_crypt(
+            read_data,
+            this.base_offset + this.offset,
+            this.ctr,
+            &this.key,
+        );
+        this.offset += read_data.len() as u64;
+        std::task::Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl<R: AsyncReadSeek> tokio::io::AsyncSeek for AsyncAes128CtrReader<R> {
+    fn start_seek(self: std::pin::Pin<&mut Self>, pos: SeekFrom) -> io::Result<()> {
+        let this = self.get_mut();
+        let inner_pos = match pos {
+            SeekFrom::Start(offset) => SeekFrom::Start(this.base_offset + offset),
+            SeekFrom::Current(offset) => SeekFrom::Start(
+                (this.base_offset + this.offset)
+                    .checked_add_signed(offset)
+                    .ok_or_else(|| {
+                        io::Error::new(
+                            io::ErrorKind::InvalidInput,
+                            "Attempted to seek before start of section",
+                        )
+                    })?,
+            ),
+            SeekFrom::End(offset) => SeekFrom::End(offset),
+        };
+
+        std::pin::Pin::new(&mut this.inner).start_seek(inner_pos)?;
+        this.needs_seek = true;
+        this.seek_in_progress = true;
+        Ok(())
+    }
+
+    fn poll_complete(
+        self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<io::Result<u64>> {
+        let this = self.get_mut();
+        if !this.seek_in_progress {
+            return std::task::Poll::Ready(Ok(this.offset));
+        }
+
+        let offset = std::task::ready!(std::pin::Pin::new(&mut this.inner).poll_complete(cx))?;
+        this.seek_in_progress = false;
+        this.needs_seek = false;
+        if offset < this.base_offset {
+            // Go back to where we were before the failed seek
+            this.needs_seek = true;
+            return std::task::Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Attempted to seek before start of section",
+            )));
+        }
+
+        this.offset = offset - this.base_offset;
+        std::task::Poll::Ready(Ok(this.offset))
     }
 }
 
@@ -155,3 +777,143 @@ impl Seek for Aes128CtrReader {
 pub fn new_shared<T>(t: T) -> Shared<T> {
     Arc::new(Mutex::new(t))
 }
+
+#[inline]
+pub fn new_shared_bytes(data: Vec<u8>) -> Shared<dyn ReadSeek> {
+    new_shared(DataReader::new(data))
+}
+
+// Reported when starting to extract each file, and periodically while copying its data
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct ProgressEvent<'a> {
+    pub file_name: &'a str,
+    pub bytes_done: u64,
+    pub total_bytes: u64,
+}
+
+pub(crate) struct ProgressTracker<'a> {
+    callback: &'a mut dyn FnMut(ProgressEvent),
+    bytes_done: u64,
+    total_bytes: u64,
+}
+
+pub const COPY_BUFFER_SIZE: usize = 0x100000;
+
+// Streams exactly `size` bytes through a fixed buffer, so memory use doesn't depend on the file size.
+// A reader ending early is an error rather than a silently truncated copy
+#[inline]
+pub fn copy_file(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + ?Sized),
+    size: u64,
+) -> Result<()> {
+    copy_file_chunks(reader, writer, size, &mut |_| {})
+}
+
+// SHA-256 of exactly `size` bytes, streamed the same way
+pub(crate) fn sha256_reader(reader: &mut (impl Read + ?Sized), size: u64) -> Result<[u8; 0x20]> {
+    let mut hasher = Sha256::new();
+    copy_file(reader, &mut hasher, size)?;
+    Ok(hasher.finalize().into())
+}
+
+// Same as copy_file, calling on_chunk with the size of every chunk written
+pub(crate) fn copy_file_chunks(
+    reader: &mut (impl Read + ?Sized),
+    writer: &mut (impl Write + ?Sized),
+    size: u64,
+    on_chunk: &mut dyn FnMut(u64),
+) -> Result<()> {
+    let mut buf = vec![0u8; std::cmp::min(size, COPY_BUFFER_SIZE as u64) as usize];
+    let mut done_size = 0u64;
+    while done_size < size {
+        let chunk_size = std::cmp::min(buf.len() as u64, size - done_size) as usize;
+        let read_size = match reader.read(&mut buf[..chunk_size]) {
+            Ok(0) => {
+                return Err(Error::UnexpectedEof(format!(
+                    "Only 0x{:X} of 0x{:X} bytes could be read",
+                    done_size, size
+                )))
+            }
+            Ok(read_size) => read_size,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into()),
+        };
+        writer.write_all(&buf[..read_size])?;
+        done_size += read_size as u64;
+        on_chunk(read_size as u64);
+    }
+
+    Ok(())
+}
+
+impl<'a> ProgressTracker<'a> {
+    pub fn new(callback: &'a mut dyn FnMut(ProgressEvent), total_bytes: u64) -> Self {
+        Self {
+            callback,
+            bytes_done: 0,
+            total_bytes,
+        }
+    }
+
+    fn report(&mut self, file_name: &str) {
+        (self.callback)(ProgressEvent {
+            file_name,
+            bytes_done: self.bytes_done,
+            total_bytes: self.total_bytes,
+        });
+    }
+
+    pub fn copy(
+        &mut self,
+        file_name: &str,
+        reader: &mut impl Read,
+        writer: &mut impl Write,
+        size: u64,
+    ) -> Result<()> {
+        self.report(file_name);
+        copy_file_chunks(reader, writer, size, &mut |chunk_size| {
+            self.bytes_done += chunk_size;
+            self.report(file_name);
+        })
+    }
+}
+
+// Copies (absolute offset, size, output path) file ranges out of the source, every worker getting its
+// own reader from open_reader so that they don't all wait on the same lock
+#[cfg(feature = "rayon")]
+pub(crate) fn extract_files_parallel<F>(
+    files: &[(u64, u64, std::path::PathBuf)],
+    open_reader: F,
+) -> Result<()>
+where
+    F: Fn() -> Result<Shared<dyn ReadSeek>> + Sync,
+{
+    use rayon::prelude::*;
+
+    files.par_iter().try_for_each_init(
+        || None,
+        |reader: &mut Option<Shared<dyn ReadSeek>>, (offset, size, out_path)| -> Result<()> {
+            if reader.is_none() {
+                *reader = Some(open_reader()?);
+            }
+
+            let mut reader = reader.as_ref().unwrap().lock_shared();
+            reader.seek(SeekFrom::Start(*offset))?;
+            let mut out_file = std::fs::File::create(out_path)?;
+            copy_file(&mut *reader, &mut out_file, *size)
+        },
+    )
+}
+
+// Container names must stay a single path item when extracted, otherwise they could escape the output dir
+pub fn check_path_item(name: &str) -> Result<&str> {
+    if name.is_empty() || (name == ".") || (name == "..") || name.contains(['/', '\\']) {
+        Err(Error::InvalidData(format!(
+            "Invalid path item name: '{}'",
+            name
+        )))
+    } else {
+        Ok(name)
+    }
+}
@@ -0,0 +1,106 @@
+use crate::error::{Error, Result};
+use crate::hfs0::HFS0;
+use crate::util::{reader_read_val, LockShared, ReadSeek, Shared};
+use std::io::SeekFrom;
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+#[repr(C)]
+pub struct Header {
+    pub header_rsa_sig_1: [u8; 0x80],
+    pub header_rsa_sig_2: [u8; 0x80],
+    pub magic: u32,
+    pub secure_area_start_page: u32,
+    pub backup_area_start_page: u32,
+    pub title_key_dec_index: u8,
+    pub rom_size: u8,
+    pub version: u8,
+    pub flags: u8,
+    pub package_id: u64,
+    pub valid_data_end_page: u32,
+    pub reserved: [u8; 0x4],
+    pub iv: [u8; 0x10],
+    pub partition_fs_header_offset: u64,
+    pub partition_fs_header_size: u64,
+    pub partition_fs_header_hash: [u8; 0x20],
+    pub initial_data_hash: [u8; 0x20],
+    pub sel_sec: u32,
+    pub sel_t1_key: u32,
+    pub sel_key: u32,
+    pub lim_area_page: u32,
+    pub encrypted_info: [u8; 0x70],
+}
+
+impl Header {
+    pub const MAGIC: u32 = u32::from_le_bytes(*b"HEAD");
+
+    pub fn get_cartridge_size(&self) -> Option<u64> {
+        const GB: u64 = 1024 * 1024 * 1024;
+        match self.rom_size {
+            0xFA => Some(GB),
+            0xF8 => Some(2 * GB),
+            0xF0 => Some(4 * GB),
+            0xE0 => Some(8 * GB),
+            0xE1 => Some(16 * GB),
+            0xE2 => Some(32 * GB),
+            _ => None,
+        }
+    }
+}
+
+pub struct XCI {
+    reader: Shared<dyn ReadSeek>,
+    pub header: Header,
+    root_partition: HFS0,
+}
+
+impl XCI {
+    pub const UPDATE_PARTITION: &'static str = "update";
+    pub const NORMAL_PARTITION: &'static str = "normal";
+    pub const SECURE_PARTITION: &'static str = "secure";
+    pub const LOGO_PARTITION: &'static str = "logo";
+
+    pub fn new(reader: Shared<dyn ReadSeek>) -> Result<Self> {
+        reader.lock_shared().seek(SeekFrom::Start(0))?;
+        let header: Header = reader_read_val(&reader)?;
+        if header.magic != Header::MAGIC {
+            return Err(Error::InvalidMagic {
+                expected: Header::MAGIC,
+                found: header.magic,
+            });
+        }
+
+        let root_partition = HFS0::new_at(reader.clone(), header.partition_fs_header_offset)?;
+
+        Ok(Self {
+            reader,
+            header,
+            root_partition,
+        })
+    }
+
+    #[inline]
+    pub fn get_cartridge_size(&self) -> Option<u64> {
+        self.header.get_cartridge_size()
+    }
+
+    #[inline]
+    pub fn get_package_id(&self) -> u64 {
+        self.header.package_id
+    }
+
+    #[inline]
+    pub fn list_partitions(&self) -> Result<Vec<String>> {
+        self.root_partition.list_files()
+    }
+
+    pub fn open_partition(&mut self, name: &str) -> Result<HFS0> {
+        let idx = self
+            .list_partitions()?
+            .iter()
+            .position(|partition_name| partition_name == name)
+            .ok_or_else(|| Error::NotFound(format!("partition '{}'", name)))?;
+
+        let partition_offset = self.root_partition.get_file_offset(idx)?;
+        HFS0::new_at(self.reader.clone(), partition_offset)
+    }
+}